@@ -0,0 +1,225 @@
+use crate::context;
+use crate::error_report;
+use crate::logging::TracedRequest;
+use crate::parameters;
+use crate::settings;
+use crate::settings_db;
+use teloxide::prelude::*;
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup};
+
+const APPROVE_PREFIX: &str = "chat_approve:";
+const DENY_PREFIX: &str = "chat_deny:";
+
+pub fn is_approval_callback(query: &CallbackQuery) -> bool {
+    query
+        .data
+        .as_deref()
+        .is_some_and(|data| data.starts_with(APPROVE_PREFIX) || data.starts_with(DENY_PREFIX))
+}
+
+/// Chats, where the bot already has data, were served before the allowlist mode was enabled,
+/// so they are approved implicitly.
+pub fn approve_existing_chats(
+    chat_ids: Vec<i64>,
+    settings_db: &settings_db::SettingsDb,
+) -> anyhow::Result<()> {
+    for chat_id in chat_ids {
+        let namespace = settings_db::Namespace::Chat(chat_id);
+        let mut approval = settings_db.get::<settings::ChatApproval>(namespace)?;
+        if approval.status.is_none() {
+            approval.status = Some(settings::ApprovalStatus::Approved);
+            settings_db.set::<settings::ChatApproval>(namespace, &approval)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns whether the bot can work in the chat. Asks the owner about chats, which were never
+/// reviewed, and leaves denied chats.
+pub async fn is_chat_allowed(
+    chat: &teloxide::types::Chat,
+    bot: &AutoSend<Bot>,
+    context: &context::HandlerContext,
+) -> bool {
+    if !context.parameters.is_allowlist_mode_enabled || chat.is_private() {
+        return true;
+    }
+
+    match check_chat(chat, bot, context).await {
+        Ok(is_allowed) => is_allowed,
+        Err(e) => {
+            log::warn!("Cannot check approval of the chat {}: {:?}", chat.id, e);
+            false
+        }
+    }
+}
+
+async fn check_chat(
+    chat: &teloxide::types::Chat,
+    bot: &AutoSend<Bot>,
+    context: &context::HandlerContext,
+) -> anyhow::Result<bool> {
+    let settings_db = &context.settings_db;
+    let parameters = &context.parameters;
+    let namespace = settings_db::Namespace::Chat(chat.id.0);
+    let approval = settings_db.get::<settings::ChatApproval>(namespace)?;
+
+    match approval.status {
+        Some(settings::ApprovalStatus::Approved) => return Ok(true),
+        Some(settings::ApprovalStatus::Denied) => {
+            log::info!("Leaving the denied chat {}", chat.id);
+            bot.leave_chat(chat.id).traced().await?;
+        }
+        Some(settings::ApprovalStatus::Pending) => {
+            // Timers don't survive restarts, so an expired request can be noticed only here
+            if is_request_expired(&approval, parameters) {
+                leave_unapproved_chat(chat.id, bot, settings_db).await?;
+            }
+        }
+        None => request_approval(chat, bot.clone(), context).await?,
+    }
+
+    Ok(false)
+}
+
+async fn request_approval(
+    chat: &teloxide::types::Chat,
+    bot: AutoSend<Bot>,
+    context: &context::HandlerContext,
+) -> anyhow::Result<()> {
+    let settings_db = &context.settings_db;
+    let parameters = &context.parameters;
+    settings_db.set::<settings::ChatApproval>(
+        settings_db::Namespace::Chat(chat.id.0),
+        &settings::ChatApprovalValue {
+            status: Some(settings::ApprovalStatus::Pending),
+            requested_at: Some(chrono::Utc::now().timestamp()),
+        },
+    )?;
+
+    let text = format!(
+        "Бот добавлен в чат «{}» ({}). Разрешить ему работать в этом чате? Без ответа бот покинет чат через {} мин.",
+        chat.title().unwrap_or_default(),
+        chat.id,
+        parameters.chat_approval_timeout.as_secs() / 60
+    );
+    let keyboard = InlineKeyboardMarkup::new(vec![vec![
+        InlineKeyboardButton::callback("Разрешить", format!("{}{}", APPROVE_PREFIX, chat.id)),
+        InlineKeyboardButton::callback("Запретить", format!("{}{}", DENY_PREFIX, chat.id)),
+    ]]);
+
+    if let Err(e) = bot
+        .send_message(UserId(parameters.owner_id), text)
+        .reply_markup(keyboard)
+        .traced()
+        .await
+    {
+        log::warn!("Cannot ask the owner about the chat {}: {:?}", chat.id, e);
+    }
+
+    let chat_id = chat.id;
+    let timeout = parameters.chat_approval_timeout;
+    let settings_db = settings_db.clone();
+    let error_reporter = context.error_reporter.clone();
+    context.tasks.spawn("expire_approval", timeout, async move {
+        let result = async {
+            let approval = settings_db
+                .get::<settings::ChatApproval>(settings_db::Namespace::Chat(chat_id.0))?;
+            if approval.status == Some(settings::ApprovalStatus::Pending) {
+                leave_unapproved_chat(chat_id, &bot, &settings_db).await?;
+            }
+            anyhow::Result::<()>::Ok(())
+        }
+        .await;
+
+        if let Err(e) = result {
+            error_reporter.report(error_report::with_chat(
+                e.context("Cannot expire the approval request"),
+                chat_id.0,
+            ));
+        }
+    });
+
+    Ok(())
+}
+
+fn is_request_expired(
+    approval: &settings::ChatApprovalValue,
+    parameters: &parameters::Parameters,
+) -> bool {
+    let requested_at = approval.requested_at.unwrap_or_default();
+    chrono::Utc::now().timestamp() - requested_at
+        >= parameters.chat_approval_timeout.as_secs() as i64
+}
+
+// The chat is asked about again, if the bot is added there once more
+async fn leave_unapproved_chat(
+    chat_id: ChatId,
+    bot: &AutoSend<Bot>,
+    settings_db: &settings_db::SettingsDb,
+) -> anyhow::Result<()> {
+    log::info!(
+        "Leaving the chat {}, which was not approved in time",
+        chat_id
+    );
+    settings_db.set::<settings::ChatApproval>(
+        settings_db::Namespace::Chat(chat_id.0),
+        &Default::default(),
+    )?;
+    bot.leave_chat(chat_id).traced().await?;
+    Ok(())
+}
+
+pub async fn approval_callback_handler(
+    query: CallbackQuery,
+    bot: AutoSend<Bot>,
+    settings_db: std::sync::Arc<settings_db::SettingsDb>,
+    parameters: std::sync::Arc<parameters::Parameters>,
+) -> anyhow::Result<()> {
+    if query.from.id.0 != parameters.owner_id {
+        bot.answer_callback_query(query.id)
+            .text("У вас недостаточно прав для выполнения данной операции!")
+            .traced()
+            .await?;
+        return Ok(());
+    }
+
+    let data = query.data.as_deref().unwrap_or_default();
+    let (status, chat_id) = if let Some(chat_id) = data.strip_prefix(APPROVE_PREFIX) {
+        (settings::ApprovalStatus::Approved, chat_id)
+    } else if let Some(chat_id) = data.strip_prefix(DENY_PREFIX) {
+        (settings::ApprovalStatus::Denied, chat_id)
+    } else {
+        bail!("Unexpected approval callback data: {}", data);
+    };
+    let chat_id = ChatId(chat_id.parse()?);
+
+    settings_db.set::<settings::ChatApproval>(
+        settings_db::Namespace::Chat(chat_id.0),
+        &settings::ChatApprovalValue {
+            status: Some(status),
+            requested_at: None,
+        },
+    )?;
+
+    let text = if status == settings::ApprovalStatus::Approved {
+        log::info!("The chat {} was approved", chat_id);
+        format!("Чат {} разрешён.", chat_id)
+    } else {
+        log::info!("The chat {} was denied", chat_id);
+        if let Err(e) = bot.leave_chat(chat_id).traced().await {
+            log::warn!("Cannot leave the chat {}: {:?}", chat_id, e);
+        }
+        format!("Чат {} запрещён, бот покинул его.", chat_id)
+    };
+
+    bot.answer_callback_query(query.id).traced().await?;
+    if let Some(message) = query.message {
+        bot.edit_message_text(message.chat.id, message.id, text)
+            .traced()
+            .await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,79 @@
+/// Starts work, which outlives the handler: delayed deletions, approval timeouts, broadcasts and
+/// error digests. In the replay mode the tasks are only kept, so the replay runs them itself
+/// after the update is handled, or reports them.
+pub struct BackgroundTasks {
+    tasks: Option<std::sync::Mutex<Vec<BackgroundTask>>>,
+}
+
+pub struct BackgroundTask {
+    pub name: &'static str,
+    pub delay: std::time::Duration,
+    task: std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send>>,
+}
+
+/// A task, which was not run by the replay, since it waits for real time to pass
+#[derive(Debug, serde::Serialize)]
+pub struct ScheduledTask {
+    pub name: &'static str,
+    pub delay_seconds: u64,
+}
+
+impl BackgroundTasks {
+    pub fn spawning() -> Self {
+        Self { tasks: None }
+    }
+
+    pub fn deferring() -> Self {
+        Self {
+            tasks: Some(Default::default()),
+        }
+    }
+
+    /// Runs the task after the delay
+    pub fn spawn<F>(&self, name: &'static str, delay: std::time::Duration, task: F)
+    where
+        F: std::future::Future<Output = ()> + Send + 'static,
+    {
+        match &self.tasks {
+            Some(tasks) => tasks
+                .lock()
+                .expect("Background tasks mutex is poisoned")
+                .push(BackgroundTask {
+                    name,
+                    delay,
+                    task: Box::pin(task),
+                }),
+            None => {
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    task.await;
+                });
+            }
+        }
+    }
+
+    /// Returns the deferred tasks, which were not taken yet
+    pub fn take(&self) -> Vec<BackgroundTask> {
+        match &self.tasks {
+            Some(tasks) => {
+                std::mem::take(&mut *tasks.lock().expect("Background tasks mutex is poisoned"))
+            }
+            None => Vec::new(),
+        }
+    }
+}
+
+impl BackgroundTask {
+    /// Runs the task at once, regardless of the delay
+    pub async fn run(self) {
+        self.task.await;
+    }
+
+    /// Drops the task and describes it
+    pub fn cancel(self) -> ScheduledTask {
+        ScheduledTask {
+            name: self.name,
+            delay_seconds: self.delay.as_secs(),
+        }
+    }
+}
@@ -0,0 +1,351 @@
+use crate::db;
+use crate::settings_db;
+
+// Bump on any incompatible change of the archive layout
+const FORMAT_VERSION: u32 = 1;
+
+const MANIFEST_FILE: &str = "manifest.json";
+const SETTINGS_FILE: &str = "settings.json";
+const CHATS_DIRECTORY: &str = "chats";
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Manifest {
+    pub format_version: u32,
+    pub created_at: String,
+    pub bot_version: String,
+    pub chat_schema_version: i64,
+    pub settings_versions: std::collections::BTreeMap<String, u32>,
+    pub chats: Vec<ChatEntry>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ChatEntry {
+    pub chat_id: i64,
+    pub schema_version: i64,
+    pub size: u64,
+}
+
+/// Writes an archive with the settings and all chat databases into `backup_root`.
+/// Databases are snapshotted one by one, so the bot can keep working meanwhile.
+pub async fn create(
+    settings_db: &settings_db::SettingsDb,
+    pool_factory: &db::SqliteDatabasePoolFactory,
+    backup_root: &std::path::Path,
+) -> anyhow::Result<std::path::PathBuf> {
+    std::fs::create_dir_all(backup_root)?;
+
+    let created_at = chrono::Utc::now();
+    let name = format!("slowpoke-backup-{}", created_at.format("%Y%m%d-%H%M%S"));
+    let staging_path = backup_root.join(format!(".{}", name));
+    let archive_path = backup_root.join(format!("{}.tar", name));
+
+    let result = stage_and_archive(
+        settings_db,
+        pool_factory,
+        &staging_path,
+        &archive_path,
+        created_at,
+    )
+    .await;
+
+    if let Err(e) = std::fs::remove_dir_all(&staging_path) {
+        log::warn!("Cannot remove the backup staging directory: {}", e);
+    }
+
+    let manifest = result?;
+    log::info!(
+        "Backup {} with {} chats was created",
+        archive_path.display(),
+        manifest.chats.len()
+    );
+
+    Ok(archive_path)
+}
+
+async fn stage_and_archive(
+    settings_db: &settings_db::SettingsDb,
+    pool_factory: &db::SqliteDatabasePoolFactory,
+    staging_path: &std::path::Path,
+    archive_path: &std::path::Path,
+    created_at: chrono::DateTime<chrono::Utc>,
+) -> anyhow::Result<Manifest> {
+    let manifest = stage(settings_db, pool_factory, staging_path, created_at).await?;
+
+    let staging_path = staging_path.to_path_buf();
+    let archive_path = archive_path.to_path_buf();
+    tokio::task::spawn_blocking(move || write_archive(&staging_path, &archive_path)).await??;
+
+    Ok(manifest)
+}
+
+async fn stage(
+    settings_db: &settings_db::SettingsDb,
+    pool_factory: &db::SqliteDatabasePoolFactory,
+    staging_path: &std::path::Path,
+    created_at: chrono::DateTime<chrono::Utc>,
+) -> anyhow::Result<Manifest> {
+    let chats_path = staging_path.join(CHATS_DIRECTORY);
+    std::fs::create_dir_all(&chats_path)?;
+
+    // Settings go first, so the archive never has settings newer than the chat databases
+    let settings = settings_db.export()?;
+    std::fs::write(
+        staging_path.join(SETTINGS_FILE),
+        serde_json::to_vec_pretty(&settings)?,
+    )?;
+
+    let mut chats = Vec::new();
+    for chat_id in pool_factory.list_existing_chats()? {
+        let client = pool_factory.create(chat_id).await?;
+        let snapshot_path = chats_path.join(format!("{}.db", chat_id));
+        client.snapshot_into(&snapshot_path).await?;
+
+        chats.push(ChatEntry {
+            chat_id,
+            schema_version: client.schema_version().await?,
+            size: std::fs::metadata(&snapshot_path)?.len(),
+        });
+    }
+
+    let manifest = Manifest {
+        format_version: FORMAT_VERSION,
+        created_at: created_at.to_rfc3339(),
+        bot_version: env!("CARGO_PKG_VERSION").to_string(),
+        chat_schema_version: db::SCHEMA_VERSION,
+        settings_versions: settings_db::SettingsDb::schema_versions(),
+        chats,
+    };
+    std::fs::write(
+        staging_path.join(MANIFEST_FILE),
+        serde_json::to_vec_pretty(&manifest)?,
+    )?;
+
+    Ok(manifest)
+}
+
+fn write_archive(
+    staging_path: &std::path::Path,
+    archive_path: &std::path::Path,
+) -> anyhow::Result<()> {
+    // Write under a temporary name, so a half-written archive is never mistaken for a backup
+    let partial_path = archive_path.with_extension("tar.partial");
+
+    let mut builder = tar::Builder::new(std::fs::File::create(&partial_path)?);
+    builder.append_dir_all(".", staging_path)?;
+    builder.into_inner()?.sync_all()?;
+
+    std::fs::rename(&partial_path, archive_path)?;
+    Ok(())
+}
+
+/// Replaces the settings database and the chat databases with the archive content.
+/// Fails, if the bot is running. Current data is kept next to the original locations.
+pub async fn restore(
+    archive_path: &std::path::Path,
+    settings_database_path: &std::path::Path,
+    chat_database_root_path: &std::path::Path,
+) -> anyhow::Result<Manifest> {
+    // A running bot would keep writing into the moved databases
+    settings_db::SettingsDb::ensure_not_in_use(settings_database_path)
+        .map_err(|e| anyhow!("The bot has to be stopped before a restore: {}", e))?;
+
+    let suffix = chrono::Utc::now().format("%Y%m%d-%H%M%S").to_string();
+    let staging_path = sibling(chat_database_root_path, &format!("restore-{}", suffix));
+
+    let result = restore_from_staging(
+        archive_path,
+        &staging_path,
+        settings_database_path,
+        chat_database_root_path,
+        &suffix,
+    )
+    .await;
+
+    if staging_path.exists() {
+        if let Err(e) = std::fs::remove_dir_all(&staging_path) {
+            log::warn!("Cannot remove the restore staging directory: {}", e);
+        }
+    }
+
+    result
+}
+
+async fn restore_from_staging(
+    archive_path: &std::path::Path,
+    staging_path: &std::path::Path,
+    settings_database_path: &std::path::Path,
+    chat_database_root_path: &std::path::Path,
+    suffix: &str,
+) -> anyhow::Result<Manifest> {
+    tar::Archive::new(std::fs::File::open(archive_path)?).unpack(staging_path)?;
+
+    let manifest: Manifest =
+        serde_json::from_slice(&std::fs::read(staging_path.join(MANIFEST_FILE))?)?;
+    let settings: std::collections::BTreeMap<String, serde_json::Value> =
+        serde_json::from_slice(&std::fs::read(staging_path.join(SETTINGS_FILE))?)?;
+
+    validate(&manifest, &settings, staging_path).await?;
+
+    // Everything is validated, so from now on only the data directories are swapped
+    for path in [settings_database_path, chat_database_root_path] {
+        if path.exists() {
+            let previous_path = sibling(path, &format!("pre-restore-{}", suffix));
+            std::fs::rename(path, &previous_path)?;
+            log::info!("Previous data was moved to {}", previous_path.display());
+        }
+    }
+
+    std::fs::rename(staging_path.join(CHATS_DIRECTORY), chat_database_root_path)?;
+    settings_db::SettingsDb::new(settings_database_path)?.import(&settings)?;
+
+    Ok(manifest)
+}
+
+async fn validate(
+    manifest: &Manifest,
+    settings: &std::collections::BTreeMap<String, serde_json::Value>,
+    staging_path: &std::path::Path,
+) -> anyhow::Result<()> {
+    if manifest.format_version != FORMAT_VERSION {
+        bail!(
+            "Unsupported backup format version {}, expected {}",
+            manifest.format_version,
+            FORMAT_VERSION
+        );
+    }
+
+    if manifest.chat_schema_version > db::SCHEMA_VERSION {
+        bail!(
+            "Backup was created with a newer chat database schema {}",
+            manifest.chat_schema_version
+        );
+    }
+
+    for chat in &manifest.chats {
+        let path = staging_path
+            .join(CHATS_DIRECTORY)
+            .join(format!("{}.db", chat.chat_id));
+
+        let schema_version = db::verify_database_file(&path)
+            .await
+            .map_err(|e| anyhow!("Chat {} database is invalid: {}", chat.chat_id, e))?;
+        if schema_version != chat.schema_version {
+            bail!(
+                "Chat {} has schema version {}, but the manifest says {}",
+                chat.chat_id,
+                schema_version,
+                chat.schema_version
+            );
+        }
+    }
+
+    settings_db::SettingsDb::validate_export(settings)
+}
+
+fn sibling(path: &std::path::Path, suffix: &str) -> std::path::PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}", suffix));
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detection::{Detector, Fingerprint};
+    use crate::settings;
+
+    const CHAT_ID: i64 = -100;
+
+    #[tokio::test]
+    async fn restored_backup_has_the_settings_and_the_chats() {
+        let root = tempfile::tempdir().unwrap();
+        let settings_path = root.path().join("settings");
+        let chats_path = root.path().join("chats");
+        std::fs::create_dir(&chats_path).unwrap();
+
+        let archive_path = {
+            let settings_db = settings_db::SettingsDb::new(&settings_path).unwrap();
+            settings_db
+                .set::<settings::LinkedChannelId>(
+                    settings_db::Namespace::Chat(CHAT_ID),
+                    &Some(-1001),
+                )
+                .unwrap();
+            let pool_factory = db::SqliteDatabasePoolFactory::new(chats_path.clone(), 1);
+            let fingerprint = Fingerprint {
+                detector: Detector::Link,
+                value: "example.com".to_string(),
+            };
+            pool_factory
+                .create(CHAT_ID)
+                .await
+                .unwrap()
+                .add_fingerprint(&fingerprint, 1, Some(1), chrono::Utc::now())
+                .await
+                .unwrap();
+
+            create(&settings_db, &pool_factory, &root.path().join("backups"))
+                .await
+                .unwrap()
+        };
+
+        let restored_settings_path = root.path().join("restored-settings");
+        let restored_chats_path = root.path().join("restored-chats");
+        let manifest = restore(&archive_path, &restored_settings_path, &restored_chats_path)
+            .await
+            .unwrap();
+        assert_eq!(manifest.chats.len(), 1);
+        assert_eq!(manifest.chats[0].chat_id, CHAT_ID);
+
+        let settings_db = settings_db::SettingsDb::new(&restored_settings_path).unwrap();
+        assert_eq!(
+            settings_db
+                .get::<settings::LinkedChannelId>(settings_db::Namespace::Chat(CHAT_ID))
+                .unwrap(),
+            Some(-1001)
+        );
+        let pool_factory = db::SqliteDatabasePoolFactory::new(restored_chats_path, 1);
+        let fingerprint = Fingerprint {
+            detector: Detector::Link,
+            value: "example.com".to_string(),
+        };
+        assert!(pool_factory
+            .create(CHAT_ID)
+            .await
+            .unwrap()
+            .find_fingerprint(&fingerprint, chrono::Utc::now())
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn restore_fails_while_the_bot_is_running() {
+        let root = tempfile::tempdir().unwrap();
+        let settings_path = root.path().join("settings");
+        let chats_path = root.path().join("chats");
+        std::fs::create_dir(&chats_path).unwrap();
+
+        let settings_db = settings_db::SettingsDb::new(&settings_path).unwrap();
+        let pool_factory = db::SqliteDatabasePoolFactory::new(chats_path.clone(), 1);
+        pool_factory.create(CHAT_ID).await.unwrap();
+        let archive_path = create(&settings_db, &pool_factory, &root.path().join("backups"))
+            .await
+            .unwrap();
+
+        match restore(&archive_path, &settings_path, &chats_path).await {
+            Ok(_) => panic!("Restored while the settings database is open"),
+            Err(e) => assert!(e.to_string().contains("has to be stopped")),
+        }
+        // Nothing was moved away or left behind
+        assert!(pool_factory.database_path(CHAT_ID).exists());
+        let staged = std::fs::read_dir(root.path())
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_string_lossy().contains("restore")
+            })
+            .count();
+        assert_eq!(staged, 0);
+    }
+}
@@ -0,0 +1,101 @@
+use crate::db;
+use crate::logging::TracedRequest;
+use crate::parameters;
+use crate::settings;
+use crate::settings_db;
+use teloxide::prelude::*;
+
+// Telegram allows about 30 messages per second to different chats
+const DELIVERY_INTERVAL: std::time::Duration = std::time::Duration::from_millis(50);
+
+pub struct BroadcastStats {
+    pub delivered: usize,
+    pub failed: usize,
+}
+
+/// Groups and channels, which the bot serves. Private chats are not included, they may have data
+/// only because someone checked a message in a dialogue with the bot.
+pub fn target_chats(
+    pool_factory: &db::SqliteDatabasePoolFactory,
+    settings_db: &settings_db::SettingsDb,
+    parameters: &parameters::Parameters,
+) -> anyhow::Result<Vec<i64>> {
+    let mut chats = Vec::new();
+
+    for chat_id in pool_factory.list_existing_chats()? {
+        if chat_id > 0 {
+            continue;
+        }
+
+        let namespace = settings_db::Namespace::Chat(chat_id);
+        if settings_db.get::<settings::RemovedAt>(namespace)?.is_some() {
+            continue;
+        }
+        if parameters.is_allowlist_mode_enabled {
+            let approval = settings_db.get::<settings::ChatApproval>(namespace)?;
+            if approval.status != Some(settings::ApprovalStatus::Approved) {
+                continue;
+            }
+        }
+
+        chats.push(chat_id);
+    }
+
+    Ok(chats)
+}
+
+/// Copies the message with its media to every chat
+pub async fn broadcast(bot: &AutoSend<Bot>, source: &Message, chats: &[i64]) -> BroadcastStats {
+    let mut stats = BroadcastStats {
+        delivered: 0,
+        failed: 0,
+    };
+
+    for &chat_id in chats {
+        match deliver(bot, source, ChatId(chat_id)).await {
+            Ok(_) => stats.delivered += 1,
+            Err(e) => {
+                log::warn!(
+                    "Cannot deliver a broadcast to the chat {}: {:?}",
+                    chat_id,
+                    e
+                );
+                stats.failed += 1;
+            }
+        }
+        tokio::time::sleep(DELIVERY_INTERVAL).await;
+    }
+
+    log::info!(
+        "Broadcast finished: {} delivered, {} failed",
+        stats.delivered,
+        stats.failed
+    );
+    stats
+}
+
+// Telegram tells how long to wait, when the flood limit is hit anyway
+async fn deliver(
+    bot: &AutoSend<Bot>,
+    source: &Message,
+    chat_id: ChatId,
+) -> Result<(), teloxide::RequestError> {
+    let result = bot
+        .copy_message(chat_id, source.chat.id, source.id)
+        .traced()
+        .await;
+
+    match result {
+        Err(teloxide::RequestError::RetryAfter(delay)) => {
+            tokio::time::sleep(delay).await;
+            bot.copy_message(chat_id, source.chat.id, source.id)
+                .traced()
+                .await?;
+        }
+        result => {
+            result?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,64 @@
+use crate::journal;
+use crate::logging::TracedRequest;
+use crate::settings;
+use crate::settings_db;
+use crate::utils;
+use teloxide::prelude::*;
+
+/// A reply in a channel would be public, so repost warnings go to the alert chat of the
+/// channel or to its creator.
+pub async fn send_alert(
+    msg: &Message,
+    bot: &AutoSend<Bot>,
+    settings_db: &settings_db::SettingsDb,
+    journal: &journal::ReplyJournal,
+    reason: &utils::SlowpokeReason,
+) -> anyhow::Result<()> {
+    journal.record(msg.chat.id.0, msg.id, reason);
+
+    let alert_chat_id = match settings_db
+        .get::<settings::ChannelAlertChat>(settings_db::Namespace::Chat(msg.chat.id.0))?
+    {
+        Some(alert_chat_id) => ChatId(alert_chat_id),
+        None => match channel_creator(msg.chat.id, bot).await? {
+            Some(creator_id) => creator_id.into(),
+            None => {
+                log::warn!(
+                    "Cannot find where to send alerts for the channel {}",
+                    msg.chat.id
+                );
+                return Ok(());
+            }
+        },
+    };
+
+    let mut text = format!(
+        "Слоупок в канале «{}»: {}.",
+        msg.chat.title().unwrap_or_default(),
+        reason
+    );
+    if let Some(url) = msg.url() {
+        text.push_str(&format!("\n{}", url));
+    }
+
+    // The creator may have never started a dialogue with the bot
+    if let Err(e) = bot.send_message(alert_chat_id, text).traced().await {
+        log::warn!(
+            "Cannot send an alert about the channel {} to the chat {}: {:?}",
+            msg.chat.id,
+            alert_chat_id,
+            e
+        );
+    }
+
+    Ok(())
+}
+
+async fn channel_creator(chat_id: ChatId, bot: &AutoSend<Bot>) -> anyhow::Result<Option<UserId>> {
+    let administrators = bot.get_chat_administrators(chat_id).traced().await?;
+
+    Ok(administrators
+        .into_iter()
+        .find(|administrator| administrator.is_owner())
+        .map(|administrator| administrator.user.id))
+}
@@ -0,0 +1,38 @@
+use crate::db;
+use crate::settings;
+use crate::settings_db;
+use teloxide::prelude::*;
+
+pub fn is_migration(msg: &Message) -> bool {
+    msg.migrate_to_chat_id().is_some() || msg.migrate_from_chat_id().is_some()
+}
+
+/// A group, which becomes a supergroup, gets a new id. Both chats get a service message,
+/// whichever comes first moves the data.
+pub async fn migration_handler(
+    msg: Message,
+    pool_factory: std::sync::Arc<db::SqliteDatabasePoolFactory>,
+    settings_db: std::sync::Arc<settings_db::SettingsDb>,
+) -> anyhow::Result<()> {
+    let (old_chat_id, new_chat_id) = match (msg.migrate_to_chat_id(), msg.migrate_from_chat_id()) {
+        (Some(new_chat_id), _) => (msg.chat.id, new_chat_id),
+        (_, Some(old_chat_id)) => (old_chat_id, msg.chat.id),
+        _ => return Ok(()),
+    };
+
+    settings_db.move_chat(old_chat_id.0, new_chat_id.0)?;
+    // The bot may have been marked as removed from the old group, but it is in the new one
+    settings_db.set::<settings::RemovedAt>(settings_db::Namespace::Chat(new_chat_id.0), &None)?;
+    if pool_factory
+        .migrate_chat(old_chat_id.0, new_chat_id.0)
+        .await?
+    {
+        log::info!(
+            "The chat {} was migrated to the chat {}",
+            old_chat_id,
+            new_chat_id
+        );
+    }
+
+    Ok(())
+}
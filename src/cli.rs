@@ -0,0 +1,258 @@
+use crate::backup;
+use crate::db;
+use crate::maintenance;
+use crate::parameters;
+use crate::replay;
+use crate::settings_db;
+
+/// Just reminds you (in some way) that you are like Slowpoke :)
+///
+/// Without a subcommand the bot is started. Subcommands work with the bot data directly and
+/// don't need Telegram access.
+#[derive(clap::Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+}
+
+#[derive(clap::Subcommand)]
+pub enum CliCommand {
+    /// Restore settings and chat databases from a backup archive. The bot has to be stopped.
+    Restore {
+        /// Archive created by the /backup command
+        archive: std::path::PathBuf,
+    },
+    /// List chats with stored data
+    Chats,
+    /// Show the most recent fingerprints stored for a chat
+    Dump {
+        #[arg(allow_negative_numbers = true)]
+        chat_id: i64,
+        #[arg(long, default_value_t = 20)]
+        limit: i64,
+    },
+    /// Remove all stored data of a chat, including its settings
+    Purge {
+        #[arg(allow_negative_numbers = true)]
+        chat_id: i64,
+    },
+    /// Apply retention and reclaim space in all chat databases once. Uses the same environment
+    /// as the bot, which has to be stopped.
+    Maintain,
+    /// Run recorded updates through the bot handlers and report the replies it would send
+    Replay {
+        /// File with one Telegram update in JSON per line
+        input: std::path::PathBuf,
+        /// Where to write the report, stdout by default
+        #[arg(long)]
+        output: Option<std::path::PathBuf>,
+        /// Directory with bot data to start from, e.g. a restored backup. It is modified by the
+        /// replay. Empty storage is used by default.
+        #[arg(long)]
+        data_dir: Option<std::path::PathBuf>,
+        /// User, which is treated as the bot owner
+        #[arg(long, default_value_t = 0)]
+        owner_id: u64,
+    },
+    /// Inspect and change settings. The bot has to be stopped.
+    #[command(subcommand)]
+    Setting(SettingCommand),
+}
+
+#[derive(clap::Subcommand)]
+pub enum SettingCommand {
+    /// List global settings or settings of a chat
+    List {
+        #[arg(long, allow_negative_numbers = true)]
+        chat: Option<i64>,
+    },
+    /// Print a setting value
+    Get {
+        key: String,
+        /// Chat for chat-scoped settings
+        #[arg(long, allow_negative_numbers = true)]
+        chat: Option<i64>,
+    },
+    /// Change a setting, the value is JSON
+    Set {
+        key: String,
+        value: String,
+        /// Chat for chat-scoped settings
+        #[arg(long, allow_negative_numbers = true)]
+        chat: Option<i64>,
+    },
+    /// Reset a setting to its default value
+    Reset {
+        key: String,
+        /// Chat for chat-scoped settings
+        #[arg(long, allow_negative_numbers = true)]
+        chat: Option<i64>,
+    },
+}
+
+pub async fn execute(command: CliCommand) -> anyhow::Result<()> {
+    match command {
+        CliCommand::Restore { archive } => {
+            let manifest = backup::restore(
+                archive.as_path(),
+                parameters::settings_database_path().as_path(),
+                parameters::chat_database_root_path().as_path(),
+            )
+            .await?;
+
+            println!(
+                "Restored {} chats from the backup created at {} by version {}",
+                manifest.chats.len(),
+                manifest.created_at,
+                manifest.bot_version
+            );
+        }
+        CliCommand::Chats => {
+            let pool_factory = open_pool_factory();
+
+            println!("{:>20} {:>10} {:>12}", "chat", "rows", "size");
+            for chat_id in pool_factory.list_existing_chats()? {
+                let client = pool_factory.open_read_only(chat_id).await?;
+                let rows = client.count_fingerprints().await?;
+                client.close().await;
+                let size = std::fs::metadata(pool_factory.database_path(chat_id))?.len();
+                println!("{:>20} {:>10} {:>12}", chat_id, rows, size);
+            }
+        }
+        CliCommand::Dump { chat_id, limit } => {
+            let pool_factory = open_pool_factory();
+            ensure_chat_exists(&pool_factory, chat_id)?;
+
+            let client = pool_factory.open_read_only(chat_id).await?;
+            for (detector, value, message_id, timestamp) in
+                client.recent_fingerprints(limit).await?
+            {
+                // Fingerprints converted or moved from another chat have no message id
+                if message_id == 0 {
+                    println!("{} {} {}", timestamp, detector, value);
+                } else {
+                    println!(
+                        "{} {} {} message {}",
+                        timestamp, detector, value, message_id
+                    );
+                }
+            }
+            client.close().await;
+        }
+        CliCommand::Purge { chat_id } => {
+            let pool_factory = open_pool_factory();
+            ensure_chat_exists(&pool_factory, chat_id)?;
+
+            pool_factory.purge(chat_id).await?;
+            open_settings_db()?.remove_chat(chat_id)?;
+            println!("Chat {} was purged", chat_id);
+        }
+        CliCommand::Maintain => {
+            let parameters = parameters::Parameters::new();
+            let pool_factory = open_pool_factory();
+            let settings_db = open_settings_db()?;
+
+            for error in maintenance::Maintenance::default()
+                .run(&pool_factory, &settings_db, &parameters)
+                .await
+            {
+                log::warn!("{:?}", error);
+            }
+            for report in pool_factory.take_quarantine_reports() {
+                println!("{}", maintenance::quarantine_text(&report));
+            }
+            println!("Maintenance finished");
+        }
+        CliCommand::Replay {
+            input,
+            output,
+            data_dir,
+            owner_id,
+        } => {
+            let mut output: Box<dyn std::io::Write> = match output {
+                Some(path) => Box::new(std::io::BufWriter::new(std::fs::File::create(path)?)),
+                None => Box::new(std::io::stdout().lock()),
+            };
+
+            let stats =
+                replay::replay(&input, output.as_mut(), data_dir.as_deref(), owner_id).await?;
+            output.flush()?;
+
+            eprintln!(
+                "Replayed {} updates ({} skipped), {} of them got replies",
+                stats.updates, stats.skipped, stats.updates_with_replies
+            );
+        }
+        CliCommand::Setting(command) => execute_setting_command(command)?,
+    }
+
+    Ok(())
+}
+
+fn execute_setting_command(command: SettingCommand) -> anyhow::Result<()> {
+    let settings_db = open_settings_db()?;
+
+    match command {
+        SettingCommand::List { chat } => {
+            let namespace =
+                chat.map_or(settings_db::Namespace::Global, settings_db::Namespace::Chat);
+            for entry in settings_db.list(namespace)? {
+                println!(
+                    "{} = {}{}",
+                    entry.descriptor.key,
+                    entry.value,
+                    if entry.is_default { " (default)" } else { "" }
+                );
+            }
+        }
+        SettingCommand::Get { key, chat } => {
+            let namespace = namespace(&settings_db, &key, chat)?;
+            println!("{}", settings_db.get_raw(namespace, &key)?.value);
+        }
+        SettingCommand::Set { key, value, chat } => {
+            let namespace = namespace(&settings_db, &key, chat)?;
+            settings_db.set_raw(namespace, &key, serde_json::from_str(&value)?)?;
+        }
+        SettingCommand::Reset { key, chat } => {
+            let namespace = namespace(&settings_db, &key, chat)?;
+            settings_db.reset_raw(namespace, &key)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn namespace(
+    settings_db: &settings_db::SettingsDb,
+    key: &str,
+    chat: Option<i64>,
+) -> anyhow::Result<settings_db::Namespace> {
+    match (settings_db.descriptor(key)?.scope, chat) {
+        (settings_db::Scope::Global, None) => Ok(settings_db::Namespace::Global),
+        (settings_db::Scope::Chat, Some(chat_id)) => Ok(settings_db::Namespace::Chat(chat_id)),
+        (settings_db::Scope::Global, Some(_)) => bail!("Setting {} is global", key),
+        (settings_db::Scope::Chat, None) => bail!("Setting {} requires --chat", key),
+        (settings_db::Scope::User, _) => bail!("Setting {} is managed by users", key),
+    }
+}
+
+fn open_pool_factory() -> db::SqliteDatabasePoolFactory {
+    db::SqliteDatabasePoolFactory::new(parameters::chat_database_root_path(), 1)
+}
+
+fn open_settings_db() -> anyhow::Result<settings_db::SettingsDb> {
+    settings_db::SettingsDb::new(parameters::settings_database_path().as_path())
+        .map_err(|e| anyhow!("Cannot open settings database, is the bot running? {}", e))
+}
+
+fn ensure_chat_exists(
+    pool_factory: &db::SqliteDatabasePoolFactory,
+    chat_id: i64,
+) -> anyhow::Result<()> {
+    if !pool_factory.database_path(chat_id).exists() {
+        bail!("There is no data for chat {}", chat_id);
+    }
+
+    Ok(())
+}
@@ -1,4 +1,13 @@
+use crate::backup;
+use crate::broadcast;
+use crate::context;
+use crate::db;
+use crate::logging::TracedRequest;
+use crate::moderation;
+use crate::privacy;
+use crate::settings;
 use crate::settings_db;
+use crate::status;
 use crate::utils;
 use teloxide::{prelude::*, utils::command::BotCommands};
 
@@ -11,29 +20,192 @@ pub enum Command {
     Help,
     #[command(description = "set reply image")]
     SetImage,
+    #[command(description = "add an alternate image for the inline mode")]
+    AddImage,
+    #[command(description = "remove an alternate image: /removeimage <number>")]
+    RemoveImage(String),
     #[command(description = "throw a slowpoke")]
     Slowpoke,
+    #[command(description = "list settings")]
+    Settings,
+    #[command(description = "change a setting: /set <name> <json value>")]
+    Set(String),
+    #[command(description = "reset a setting to its default value")]
+    Reset(String),
+    #[command(description = "back up all bot data")]
+    Backup,
+    #[command(description = "shadow mode: /shadow on, /shadow off or /shadow for a summary")]
+    Shadow(String),
+    #[command(description = "exempt from detection: /exempt source|user|domain <value>")]
+    Exempt(String),
+    #[command(description = "remove an exemption: /unexempt source|user|domain <value>")]
+    Unexempt(String),
+    #[command(description = "list exemptions of the chat")]
+    Exemptions,
+    #[command(description = "forget the content of the replied message")]
+    Forget,
+    #[command(
+        description = "what to do with duplicates: /action reply, /action reply_and_delete <seconds> or /action delete"
+    )]
+    Action(String),
+    #[command(
+        description = "send repost warnings of a channel to this chat: /alerts <channel id>, /alerts <channel id> off"
+    )]
+    Alerts(String),
+    #[command(description = "delete all your data from the bot")]
+    ForgetMe,
+    #[command(description = "never slowpoke or record your messages")]
+    OptOut,
+    #[command(description = "cancel /optout")]
+    OptIn,
+}
+
+// Commands about the bot as a whole. Only the owner can use them, so they are not listed.
+#[derive(Clone, teloxide::utils::command::BotCommands)]
+#[command(rename = "lowercase")]
+pub enum OwnerCommand {
+    #[command(description = "show recent errors")]
+    Errors,
+    #[command(description = "send the replied message to all chats")]
+    Broadcast,
+    #[command(description = "show the state of the bot")]
+    Status,
+}
+
+static PERMISSION_DENIED: &str = "У вас недостаточно прав для выполнения данной операции!";
+
+pub async fn owner_command_handler(
+    msg: Message,
+    bot: AutoSend<Bot>,
+    command: OwnerCommand,
+    context: std::sync::Arc<context::HandlerContext>,
+) -> anyhow::Result<()> {
+    let context::HandlerContext {
+        settings_db,
+        pool_factory,
+        parameters,
+        error_reporter,
+        runtime,
+        tasks,
+        ..
+    } = &*context;
+    static MISSED_BROADCAST_REPLY: &str =
+        "Чтобы сделать рассылку, ответьте командой /broadcast на сообщение, которое нужно разослать.";
+
+    if !utils::is_sender_an_owner(&msg.from(), parameters.owner_id) {
+        bot.send_message(msg.chat.id, PERMISSION_DENIED)
+            .reply_to_message_id(msg.id)
+            .traced()
+            .await?;
+        return Ok(());
+    }
+
+    let text = match command {
+        OwnerCommand::Errors => error_reporter.recent_failures_text(),
+        OwnerCommand::Broadcast => match msg.reply_to_message() {
+            Some(source) => {
+                let chats = broadcast::target_chats(pool_factory, settings_db, parameters)?;
+                let source = source.clone();
+                let report_bot = bot.clone();
+                let report_to = msg.clone();
+                let chat_count = chats.len();
+
+                // Sending to every chat takes a while, so the dispatcher is not blocked
+                tasks.spawn("broadcast", std::time::Duration::ZERO, async move {
+                    let stats = broadcast::broadcast(&report_bot, &source, &chats).await;
+                    let text = format!(
+                        "Рассылка завершена: доставлено {}, ошибок {}.",
+                        stats.delivered, stats.failed
+                    );
+                    if let Err(e) = report_bot
+                        .send_message(report_to.chat.id, text)
+                        .reply_to_message_id(report_to.id)
+                        .traced()
+                        .await
+                    {
+                        log::warn!("Cannot report the broadcast result: {:?}", e);
+                    }
+                });
+
+                format!("Рассылка начата, чатов: {}.", chat_count)
+            }
+            None => MISSED_BROADCAST_REPLY.to_string(),
+        },
+        OwnerCommand::Status => {
+            status::status_text(&bot, settings_db, pool_factory, parameters, runtime).await?
+        }
+    };
+    bot.send_message(msg.chat.id, text)
+        .reply_to_message_id(msg.id)
+        .traced()
+        .await?;
+
+    Ok(())
 }
 
 pub async fn command_handler(
     msg: Message,
     bot: AutoSend<Bot>,
     command: Command,
-    owner_id: u64,
-    settings_db: std::sync::Arc<tokio::sync::Mutex<settings_db::SettingsDb>>,
+    context: std::sync::Arc<context::HandlerContext>,
 ) -> anyhow::Result<()> {
+    let context::HandlerContext {
+        settings_db,
+        pool_factory,
+        parameters,
+        journal,
+        limiter,
+        error_reporter,
+        ..
+    } = &*context;
+    let owner_id = parameters.owner_id;
+
     static ABOUT_TEXT: &str = "По всем замечаниям или предложениям обращаться сюда:\
         https://github.com/ZaMaZaN4iK/slowpoke-telegram . Спасибо!";
 
-    static HELP_TEXT: &str = "Бот просто определяет, являетесь ли вы Слоупоком или нет :)";
+    static HELP_TEXT: &str = "Бот просто определяет, являетесь ли вы Слоупоком или нет :) \
+        Бот хранит отпечатки сообщений вместе с их авторами. Удалить свои данные можно командой /forgetme, \
+        запретить боту проверять и запоминать свои сообщения - командой /optout, отменить запрет - командой /optin.";
     static HELP_TEXT_FOR_ADMIN: &str =
-        "Чтобы установить изображение для бота, ответьте командой /setimage на сообщение с изображением.";
-    static PERMISSION_DENIED: &str = "У вас недостаточно прав для выполнения данной операции!";
+        "Чтобы установить изображение для бота, ответьте командой /setimage на сообщение с изображением, \
+        дополнительные изображения для инлайн-режима добавляются командой /addimage и удаляются командой /removeimage. \
+        Настройки можно посмотреть командой /settings, изменить командой /set и сбросить командой /reset, \
+        настройки чата могут менять и его администраторы. \
+        В теневом режиме (/shadow on) бот только запоминает дубликаты, сводка доступна по команде /shadow. \
+        Частоту ответов и тихие часы задаёт настройка reply_policy, часовой пояс чата - настройка timezone. \
+        Еженедельные итоги со слоупоком недели включаются настройкой digest. \
+        Администраторы чата могут исключить из проверки источник репостов, пользователя или домен ссылок \
+        командами /exempt и /unexempt, список исключений выводит команда /exemptions. \
+        Команда /forget в ответ на сообщение удаляет его из памяти бота. \
+        Командой /action можно включить удаление дубликатов, для этого боту нужно право удалять сообщения. \
+        Предупреждения о повторах в канале приходят создателю канала, команда /alerts направляет их в текущий чат. \
+        Последние ошибки бота показывает команда /errors, сводка ошибок приходит владельцу в личные сообщения. \
+        Владелец может разослать сообщение во все чаты, ответив на него командой /broadcast, \
+        состояние бота показывает команда /status.";
+    static SETTING_UPDATED: &str = "Настройка обновлена.";
+    static SETTING_NOT_UPDATED: &str = "Не удалось обновить настройку:";
+    static BACKUP_CREATED: &str = "Резервная копия сохранена:";
+    static BACKUP_FAILED: &str = "Не удалось создать резервную копию:";
+    static SHADOW_MODE_USAGE: &str =
+        "Используйте /shadow on, /shadow off или /shadow без аргументов.";
+    static IMAGE_ADDED: &str = "Изображение добавлено, дополнительных изображений:";
+    static IMAGE_REMOVED: &str = "Изображение удалено, дополнительных изображений:";
+    static MISSED_PHOTO_REPLY: &str =
+        "Чтобы добавить изображение, ответьте командой на сообщение с изображением.";
+    static MISSED_FORGET_REPLY: &str =
+        "Чтобы бот забыл сообщение, ответьте командой /forget на это сообщение.";
+    static ACTION_NOT_UPDATED: &str = "Не удалось изменить действие:";
+    static ALERTS_NOT_UPDATED: &str = "Не удалось настроить предупреждения:";
+    static EXEMPTIONS_NOT_UPDATED: &str = "Не удалось изменить исключения:";
+    static OPTED_OUT: &str =
+        "Бот больше не запоминает ваши сообщения и не отвечает на них. Команда /optin отменяет это.";
+    static OPTED_IN: &str = "Бот снова проверяет ваши сообщения.";
 
     match command {
         Command::About => {
             bot.send_message(msg.chat.id, ABOUT_TEXT)
                 .reply_to_message_id(msg.id)
+                .traced()
                 .await?;
         }
         Command::Help => {
@@ -44,52 +216,684 @@ pub async fn command_handler(
             };
             bot.send_message(msg.chat.id, help_text)
                 .reply_to_message_id(msg.id)
+                .traced()
                 .await?;
         }
         Command::SetImage => {
             if utils::is_sender_an_owner(&msg.from(), owner_id) {
                 if let Some(reply_message) = msg.reply_to_message() {
                     if let Some(photo) = reply_message.photo() {
-                        let first_photo = photo.first().ok_or_else(|| {
-                            anyhow!("Cannot extract a first photo from the reply")
-                        })?;
-
-                        let image_set_result = settings_db
-                            .lock()
-                            .await
-                            .add_setting("image_file_id", first_photo.file_id.as_str());
-
-                        match image_set_result {
-                            Ok(_) => log::info!("Image was updated successfully"),
-                            Err(e) => log::info!("Image was not updated successfully: {:?}", e),
-                        }
+                        settings_db.set::<settings::ReplyImage>(
+                            settings_db::Namespace::Global,
+                            &Some(largest_image(photo)?),
+                        )?;
+                        log::info!("Image was updated successfully");
                     } else {
                         static MISSED_PHOTO_IN_MESSAGE: &str =
                             "Не могу обнаружить фото в цитируемом сообщении.";
                         bot.send_message(msg.chat.id, MISSED_PHOTO_IN_MESSAGE)
                             .reply_to_message_id(msg.id)
+                            .traced()
                             .await?;
                     }
                 } else {
                     static MISSED_REPLY_MESSAGE: &str = "Чтобы установить изображение, Вам необходимо ответить на сообщение с требуемым изображнием";
                     bot.send_message(msg.chat.id, MISSED_REPLY_MESSAGE)
                         .reply_to_message_id(msg.id)
+                        .traced()
                         .await?;
                 }
             } else {
                 bot.send_message(msg.chat.id, PERMISSION_DENIED)
                     .reply_to_message_id(msg.id)
+                    .traced()
                     .await?;
             }
         }
+        Command::AddImage => {
+            let text = if utils::is_sender_an_owner(&msg.from(), owner_id) {
+                match msg.reply_to_message().and_then(|reply| reply.photo()) {
+                    Some(photo) => {
+                        let namespace = settings_db::Namespace::Global;
+                        let mut images = settings_db.get::<settings::AlternateImages>(namespace)?;
+                        if images.len() >= settings::MAX_ALTERNATE_IMAGES {
+                            format!(
+                                "Дополнительных изображений не может быть больше {}, удалите лишние командой /removeimage.",
+                                settings::MAX_ALTERNATE_IMAGES
+                            )
+                        } else {
+                            images.push(largest_image(photo)?);
+                            settings_db.set::<settings::AlternateImages>(namespace, &images)?;
+                            format!("{} {}", IMAGE_ADDED, images.len())
+                        }
+                    }
+                    None => MISSED_PHOTO_REPLY.to_string(),
+                }
+            } else {
+                PERMISSION_DENIED.to_string()
+            };
+            bot.send_message(msg.chat.id, text)
+                .reply_to_message_id(msg.id)
+                .traced()
+                .await?;
+        }
+        Command::RemoveImage(number) => {
+            let text = if utils::is_sender_an_owner(&msg.from(), owner_id) {
+                let namespace = settings_db::Namespace::Global;
+                let mut images = settings_db.get::<settings::AlternateImages>(namespace)?;
+                // Images are numbered in the order, in which they were added
+                match number.trim().parse::<usize>() {
+                    Ok(number) if (1..=images.len()).contains(&number) => {
+                        images.remove(number - 1);
+                        settings_db.set::<settings::AlternateImages>(namespace, &images)?;
+                        format!("{} {}", IMAGE_REMOVED, images.len())
+                    }
+                    _ if images.is_empty() => "Дополнительных изображений нет.".to_string(),
+                    _ => format!(
+                        "Укажите номер изображения от 1 до {}: /removeimage <номер>.",
+                        images.len()
+                    ),
+                }
+            } else {
+                PERMISSION_DENIED.to_string()
+            };
+            bot.send_message(msg.chat.id, text)
+                .reply_to_message_id(msg.id)
+                .traced()
+                .await?;
+        }
         Command::Slowpoke => {
-            if let Some(reply_message) = msg.reply_to_message() {
-                utils::send_slowpoke(reply_message.clone(), bot, settings_db).await?;
+            if let Some(user) = msg.from() {
+                if let Err(suppression) = limiter.allow_slowpoke_command(
+                    settings_db,
+                    msg.chat.id.0,
+                    user.id.0,
+                    utils::message_time(&msg),
+                )? {
+                    log::info!(
+                        "Slowpoke command from the user {} in the chat {} is suppressed by {}",
+                        user.id,
+                        msg.chat.id,
+                        suppression
+                    );
+                    return Ok(());
+                }
+            }
+
+            let target = msg.reply_to_message().cloned().unwrap_or(msg);
+            if let Some(author) = target.from() {
+                if privacy::is_opted_out(settings_db, author.id.0) {
+                    log::info!("Slowpoke command for an opted out user is ignored");
+                    return Ok(());
+                }
+            }
+            utils::send_slowpoke(
+                target,
+                bot,
+                settings_db.clone(),
+                journal.clone(),
+                utils::SlowpokeReason::Command,
+                None,
+            )
+            .await?;
+        }
+        Command::Settings => {
+            let text = if utils::is_sender_an_owner(&msg.from(), owner_id) {
+                list_settings(settings_db, msg.chat.id.0, true)?
+            } else if utils::is_sender_an_admin(&msg, &bot, owner_id).await {
+                list_settings(settings_db, msg.chat.id.0, false)?
+            } else {
+                PERMISSION_DENIED.to_string()
+            };
+            bot.send_message(msg.chat.id, text)
+                .reply_to_message_id(msg.id)
+                .traced()
+                .await?;
+        }
+        Command::Set(arguments) => {
+            let key = arguments.split_whitespace().next().unwrap_or_default();
+            let text = if can_change_setting(&msg, &bot, settings_db, key, owner_id).await {
+                match set_setting(settings_db, msg.chat.id.0, arguments.as_str()) {
+                    Ok(_) => SETTING_UPDATED.to_string(),
+                    Err(e) => format!("{} {}", SETTING_NOT_UPDATED, e),
+                }
+            } else {
+                PERMISSION_DENIED.to_string()
+            };
+            bot.send_message(msg.chat.id, text)
+                .reply_to_message_id(msg.id)
+                .traced()
+                .await?;
+        }
+        Command::Reset(key) => {
+            let key = key.trim();
+            let text = if can_change_setting(&msg, &bot, settings_db, key, owner_id).await {
+                match reset_setting(settings_db, msg.chat.id.0, key) {
+                    Ok(_) => SETTING_UPDATED.to_string(),
+                    Err(e) => format!("{} {}", SETTING_NOT_UPDATED, e),
+                }
+            } else {
+                PERMISSION_DENIED.to_string()
+            };
+            bot.send_message(msg.chat.id, text)
+                .reply_to_message_id(msg.id)
+                .traced()
+                .await?;
+        }
+        Command::Backup => {
+            let text = if utils::is_sender_an_owner(&msg.from(), owner_id) {
+                match backup::create(settings_db, pool_factory, &parameters.backup_path).await {
+                    Ok(path) => format!("{} {}", BACKUP_CREATED, path.display()),
+                    Err(e) => {
+                        log::warn!("Cannot create a backup: {:?}", e);
+                        format!("{} {}", BACKUP_FAILED, e)
+                    }
+                }
+            } else {
+                PERMISSION_DENIED.to_string()
+            };
+            bot.send_message(msg.chat.id, text)
+                .reply_to_message_id(msg.id)
+                .traced()
+                .await?;
+        }
+        Command::Shadow(argument) => {
+            let text = if utils::is_sender_an_owner(&msg.from(), owner_id) {
+                let namespace = settings_db::Namespace::Chat(msg.chat.id.0);
+                match argument.trim() {
+                    "on" => {
+                        settings_db.set::<settings::ShadowMode>(namespace, &true)?;
+                        shadow_summary(settings_db, pool_factory, msg.chat.id.0).await?
+                    }
+                    "off" => {
+                        settings_db.set::<settings::ShadowMode>(namespace, &false)?;
+                        shadow_summary(settings_db, pool_factory, msg.chat.id.0).await?
+                    }
+                    "" => shadow_summary(settings_db, pool_factory, msg.chat.id.0).await?,
+                    _ => SHADOW_MODE_USAGE.to_string(),
+                }
+            } else {
+                PERMISSION_DENIED.to_string()
+            };
+            bot.send_message(msg.chat.id, text)
+                .reply_to_message_id(msg.id)
+                .traced()
+                .await?;
+        }
+        Command::Exempt(ref arguments) | Command::Unexempt(ref arguments) => {
+            let text = if utils::is_sender_an_admin(&msg, &bot, owner_id).await {
+                let is_added = matches!(command, Command::Exempt(_));
+                match change_exemptions(settings_db, &msg, arguments.as_str(), is_added) {
+                    Ok(_) => list_exemptions(settings_db, msg.chat.id.0)?,
+                    Err(e) => format!("{} {}", EXEMPTIONS_NOT_UPDATED, e),
+                }
+            } else {
+                PERMISSION_DENIED.to_string()
+            };
+            bot.send_message(msg.chat.id, text)
+                .reply_to_message_id(msg.id)
+                .traced()
+                .await?;
+        }
+        Command::Exemptions => {
+            let text = if utils::is_sender_an_admin(&msg, &bot, owner_id).await {
+                list_exemptions(settings_db, msg.chat.id.0)?
+            } else {
+                PERMISSION_DENIED.to_string()
+            };
+            bot.send_message(msg.chat.id, text)
+                .reply_to_message_id(msg.id)
+                .traced()
+                .await?;
+        }
+        Command::Forget => {
+            let text = if utils::is_sender_an_admin(&msg, &bot, owner_id).await {
+                match msg.reply_to_message() {
+                    Some(reply) => {
+                        let client = pool_factory.create(msg.chat.id.0).await?;
+                        let removed = {
+                            let _chat_guard = client.lock_chat().await;
+                            client.remove_fingerprints(reply.id).await?
+                        };
+                        format!("Забыто отпечатков сообщения: {}", removed)
+                    }
+                    None => MISSED_FORGET_REPLY.to_string(),
+                }
+            } else {
+                PERMISSION_DENIED.to_string()
+            };
+            bot.send_message(msg.chat.id, text)
+                .reply_to_message_id(msg.id)
+                .traced()
+                .await?;
+        }
+        Command::Action(arguments) => {
+            let text = if utils::is_sender_an_admin(&msg, &bot, owner_id).await {
+                match set_action(settings_db, &bot, msg.chat.id, arguments.as_str()).await {
+                    Ok(text) => text,
+                    Err(e) => format!("{} {}", ACTION_NOT_UPDATED, e),
+                }
             } else {
-                utils::send_slowpoke(msg, bot, settings_db).await?;
+                PERMISSION_DENIED.to_string()
+            };
+            bot.send_message(msg.chat.id, text)
+                .reply_to_message_id(msg.id)
+                .traced()
+                .await?;
+        }
+        Command::Alerts(arguments) => {
+            let text =
+                match set_alert_chat(settings_db, &bot, &msg, arguments.as_str(), owner_id).await {
+                    Ok(text) => text,
+                    Err(e) => format!("{} {}", ALERTS_NOT_UPDATED, e),
+                };
+            bot.send_message(msg.chat.id, text)
+                .reply_to_message_id(msg.id)
+                .traced()
+                .await?;
+        }
+        Command::ForgetMe => {
+            let user = msg.from().ok_or_else(|| anyhow!("unknown sender"))?;
+            let stats = privacy::forget_user(pool_factory, settings_db, user.id.0).await?;
+            let mut text = format!("Удалено записей о вас: {}", stats.removed);
+            if !stats.errors.is_empty() {
+                text.push_str(&format!(
+                    ". Часть данных удалить не удалось (ошибок: {}), владелец бота получил отчёт.",
+                    stats.errors.len()
+                ));
             }
+            for e in stats.errors {
+                error_reporter.report(e);
+            }
+            bot.send_message(msg.chat.id, text)
+                .reply_to_message_id(msg.id)
+                .traced()
+                .await?;
+        }
+        Command::OptOut | Command::OptIn => {
+            let user = msg.from().ok_or_else(|| anyhow!("unknown sender"))?;
+            let is_opted_out = matches!(command, Command::OptOut);
+            privacy::set_opted_out(settings_db, user.id.0, is_opted_out)?;
+            let text = if is_opted_out { OPTED_OUT } else { OPTED_IN };
+            bot.send_message(msg.chat.id, text)
+                .reply_to_message_id(msg.id)
+                .traced()
+                .await?;
         }
     };
 
     Ok(())
 }
+
+// Channel posts have no author, so alerts are configured from the chat, which gets them,
+// by an administrator of the channel
+fn largest_image(photo: &[teloxide::types::PhotoSize]) -> anyhow::Result<settings::ImageFileId> {
+    // Sizes go from the smallest to the largest
+    let largest_photo = photo
+        .last()
+        .ok_or_else(|| anyhow!("Cannot extract a photo from the reply"))?;
+
+    Ok(settings::ImageFileId {
+        file_id: largest_photo.file_id.clone(),
+    })
+}
+
+async fn set_alert_chat(
+    settings_db: &settings_db::SettingsDb,
+    bot: &AutoSend<Bot>,
+    msg: &Message,
+    arguments: &str,
+    owner_id: u64,
+) -> anyhow::Result<String> {
+    let mut arguments = arguments.split_whitespace();
+    let channel_id: i64 = arguments
+        .next()
+        .ok_or_else(|| anyhow!("expected a channel id"))?
+        .parse()?;
+    let is_off = match arguments.next() {
+        None => false,
+        Some("off") => true,
+        Some(_) => bail!("expected \"off\" after the channel id"),
+    };
+
+    let user = msg.from().ok_or_else(|| anyhow!("unknown sender"))?;
+    let channel = bot.get_chat(ChatId(channel_id)).traced().await?;
+    if !channel.is_channel() {
+        bail!("{} is not a channel", channel_id);
+    }
+    if !utils::is_user_an_admin(&channel, user.id, bot, owner_id).await {
+        bail!("вы не администратор канала");
+    }
+
+    let namespace = settings_db::Namespace::Chat(channel_id);
+    if is_off {
+        settings_db.set::<settings::ChannelAlertChat>(namespace, &None)?;
+        Ok("Предупреждения о повторах в канале будут приходить его создателю.".to_string())
+    } else {
+        settings_db.set::<settings::ChannelAlertChat>(namespace, &Some(msg.chat.id.0))?;
+        Ok(format!(
+            "Предупреждения о повторах в канале «{}» будут приходить в этот чат.",
+            channel.title().unwrap_or_default()
+        ))
+    }
+}
+
+// Shows the current action without arguments
+async fn set_action(
+    settings_db: &settings_db::SettingsDb,
+    bot: &AutoSend<Bot>,
+    chat_id: ChatId,
+    arguments: &str,
+) -> anyhow::Result<String> {
+    let namespace = settings_db::Namespace::Chat(chat_id.0);
+    let mut action = settings_db.get::<settings::DuplicateAction>(namespace)?;
+
+    let mut arguments = arguments.split_whitespace();
+    if let Some(mode) = arguments.next() {
+        action.mode = serde_json::from_value(serde_json::Value::String(mode.to_string()))
+            .map_err(|_| anyhow!("expected reply, reply_and_delete or delete"))?;
+        if let Some(delay) = arguments.next() {
+            action.delete_after_seconds = delay.parse()?;
+        }
+
+        if action.mode != settings::ActionMode::Reply
+            && !moderation::can_delete_messages(bot, chat_id).await?
+        {
+            bail!("у бота нет права удалять сообщения в этом чате");
+        }
+        settings_db.set::<settings::DuplicateAction>(namespace, &action)?;
+    }
+
+    Ok(match action.mode {
+        settings::ActionMode::Reply => "Бот отвечает на дубликаты.".to_string(),
+        settings::ActionMode::ReplyAndDelete => format!(
+            "Бот отвечает на дубликаты и удаляет их через {} с.",
+            action.delete_after_seconds
+        ),
+        settings::ActionMode::Delete => "Бот сразу удаляет дубликаты.".to_string(),
+    })
+}
+
+// The value can be omitted for sources and users, when the command replies to a message
+fn change_exemptions(
+    settings_db: &settings_db::SettingsDb,
+    msg: &Message,
+    arguments: &str,
+    is_added: bool,
+) -> anyhow::Result<()> {
+    let arguments = arguments.trim();
+    let (kind, value) = arguments
+        .split_once(char::is_whitespace)
+        .map(|(kind, value)| (kind, value.trim()))
+        .unwrap_or((arguments, ""));
+    let reply = msg.reply_to_message();
+
+    let namespace = settings_db::Namespace::Chat(msg.chat.id.0);
+
+    match kind {
+        "source" => {
+            let source = if value.is_empty() {
+                reply
+                    .and_then(|reply| reply.forward_from_chat())
+                    .map(|chat| chat.id.0)
+                    .ok_or_else(|| anyhow!("expected a chat id or a reply to a forward"))?
+            } else {
+                value.parse()?
+            };
+            settings_db.update::<settings::Exemptions>(namespace, |exemptions| {
+                update_list(&mut exemptions.forward_sources, source, is_added)
+            })
+        }
+        "user" => {
+            let user = if value.is_empty() {
+                reply
+                    .and_then(|reply| reply.from())
+                    .map(|user| user.id.0)
+                    .ok_or_else(|| anyhow!("expected a user id or a reply to a message"))?
+            } else {
+                value.parse()?
+            };
+            settings_db.update::<settings::Exemptions>(namespace, |exemptions| {
+                update_list(&mut exemptions.users, user, is_added)
+            })
+        }
+        "domain" => {
+            let domain = settings::Exemptions::normalize_domain(value)?;
+            settings_db.update::<settings::Exemptions>(namespace, |exemptions| {
+                update_list(&mut exemptions.domains, domain.clone(), is_added)
+            })
+        }
+        _ => bail!("expected source, user or domain"),
+    }
+}
+
+fn update_list<T: PartialEq>(list: &mut Vec<T>, value: T, is_added: bool) {
+    if is_added {
+        if !list.contains(&value) {
+            list.push(value);
+        }
+    } else {
+        list.retain(|item| *item != value);
+    }
+}
+
+fn list_exemptions(settings_db: &settings_db::SettingsDb, chat_id: i64) -> anyhow::Result<String> {
+    let exemptions =
+        settings_db.get::<settings::Exemptions>(settings_db::Namespace::Chat(chat_id))?;
+
+    fn join<T: ToString>(values: &[T]) -> String {
+        if values.is_empty() {
+            "-".to_string()
+        } else {
+            values
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        }
+    }
+
+    Ok(format!(
+        "Исключения чата.\nИсточники репостов: {}\nПользователи: {}\nДомены: {}",
+        join(&exemptions.forward_sources),
+        join(&exemptions.users),
+        join(&exemptions.domains)
+    ))
+}
+
+// Chat administrators see only the settings of their chat
+fn list_settings(
+    settings_db: &settings_db::SettingsDb,
+    chat_id: i64,
+    include_global: bool,
+) -> anyhow::Result<String> {
+    let mut entries = if include_global {
+        settings_db.list(settings_db::Namespace::Global)?
+    } else {
+        Vec::new()
+    };
+    entries.extend(settings_db.list(settings_db::Namespace::Chat(chat_id))?);
+
+    let lines: Vec<String> = entries
+        .iter()
+        .map(|entry| {
+            format!(
+                "{} ({}{}) = {}\n{}",
+                entry.descriptor.key,
+                entry.descriptor.scope,
+                if entry.is_default { ", default" } else { "" },
+                entry.value,
+                entry.descriptor.description
+            )
+        })
+        .collect();
+
+    Ok(lines.join("\n\n"))
+}
+
+// Chat administrators can change the settings of their chat, global settings are changed only by
+// the owner. Unknown settings are left to the owner, who gets the error.
+async fn can_change_setting(
+    msg: &Message,
+    bot: &AutoSend<Bot>,
+    settings_db: &settings_db::SettingsDb,
+    key: &str,
+    owner_id: u64,
+) -> bool {
+    if utils::is_sender_an_owner(&msg.from(), owner_id) {
+        return true;
+    }
+
+    match settings_db.descriptor(key) {
+        Ok(descriptor)
+            if descriptor.scope == settings_db::Scope::Chat && !descriptor.is_internal =>
+        {
+            utils::is_sender_an_admin(msg, bot, owner_id).await
+        }
+        _ => false,
+    }
+}
+
+fn set_setting(
+    settings_db: &settings_db::SettingsDb,
+    chat_id: i64,
+    arguments: &str,
+) -> anyhow::Result<()> {
+    let (key, value) = arguments
+        .trim()
+        .split_once(char::is_whitespace)
+        .ok_or_else(|| anyhow!("expected a setting name and a value"))?;
+    let descriptor = settings_db.descriptor(key)?;
+    let value: serde_json::Value = serde_json::from_str(value.trim())?;
+
+    settings_db.set_raw(
+        settings_db::Namespace::for_chat(descriptor.scope, chat_id)?,
+        key,
+        value,
+    )
+}
+
+fn reset_setting(
+    settings_db: &settings_db::SettingsDb,
+    chat_id: i64,
+    key: &str,
+) -> anyhow::Result<()> {
+    let descriptor = settings_db.descriptor(key)?;
+    settings_db.reset_raw(
+        settings_db::Namespace::for_chat(descriptor.scope, chat_id)?,
+        key,
+    )
+}
+
+async fn shadow_summary(
+    settings_db: &settings_db::SettingsDb,
+    pool_factory: &db::SqliteDatabasePoolFactory,
+    chat_id: i64,
+) -> anyhow::Result<String> {
+    static SUMMARY_DAYS: i64 = 7;
+    static RECENT_DETECTIONS_LIMIT: i64 = 10;
+
+    let is_shadow =
+        settings_db.get::<settings::ShadowMode>(settings_db::Namespace::Chat(chat_id))?;
+    let client = pool_factory.create(chat_id).await?;
+
+    let mut text = format!(
+        "Теневой режим {}.\nНе отправлено слоупоков за последние {} дней:",
+        if is_shadow {
+            "включён"
+        } else {
+            "выключен"
+        },
+        SUMMARY_DAYS
+    );
+
+    let counts = client.shadow_detection_counts(SUMMARY_DAYS).await?;
+    if counts.is_empty() {
+        text.push_str(" 0");
+    }
+    for (detector, count) in counts {
+        text.push_str(&format!("\n{}: {}", detector, count));
+    }
+
+    let recent_detections = client
+        .recent_shadow_detections(RECENT_DETECTIONS_LIMIT)
+        .await?;
+    if !recent_detections.is_empty() {
+        text.push_str("\n\nПоследние:");
+    }
+    for (message_id, reason, timestamp) in recent_detections {
+        // Messages moved from a group, which became a supergroup, have no ids
+        if message_id == 0 {
+            text.push_str(&format!("\n{}: {}", timestamp, reason));
+        } else {
+            text.push_str(&format!(
+                "\n{} сообщение {}: {}",
+                timestamp, message_id, reason
+            ));
+        }
+    }
+
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHAT_ID: i64 = -1001;
+
+    #[test]
+    fn chat_settings_can_be_changed() {
+        let settings_db = settings_db::SettingsDb::temporary().unwrap();
+        set_setting(&settings_db, CHAT_ID, "shadow_mode true").unwrap();
+        assert!(settings_db
+            .get::<settings::ShadowMode>(settings_db::Namespace::Chat(CHAT_ID))
+            .unwrap());
+
+        reset_setting(&settings_db, CHAT_ID, "shadow_mode").unwrap();
+        assert!(!settings_db
+            .get::<settings::ShadowMode>(settings_db::Namespace::Chat(CHAT_ID))
+            .unwrap());
+    }
+
+    #[test]
+    fn internal_settings_cannot_be_changed() {
+        let settings_db = settings_db::SettingsDb::temporary().unwrap();
+        let namespace = settings_db::Namespace::Chat(CHAT_ID);
+        settings_db
+            .set::<settings::RemovedAt>(namespace, &Some(1))
+            .unwrap();
+
+        for arguments in [
+            "approval {\"status\": \"approved\"}",
+            "linked_channel_id -1002",
+            "removed_at 0",
+            "digest_sent_at 0",
+            "opted_out true",
+        ] {
+            assert!(set_setting(&settings_db, CHAT_ID, arguments).is_err());
+        }
+        for key in [
+            "approval",
+            "linked_channel_id",
+            "removed_at",
+            "digest_sent_at",
+        ] {
+            assert!(reset_setting(&settings_db, CHAT_ID, key).is_err());
+        }
+
+        assert_eq!(
+            settings_db.get::<settings::RemovedAt>(namespace).unwrap(),
+            Some(1)
+        );
+        assert_eq!(
+            settings_db
+                .get::<settings::LinkedChannelId>(namespace)
+                .unwrap(),
+            None
+        );
+        assert!(!list_settings(&settings_db, CHAT_ID, true)
+            .unwrap()
+            .contains("removed_at"));
+    }
+}
@@ -0,0 +1,46 @@
+use crate::background;
+use crate::db;
+use crate::error_report;
+use crate::journal;
+use crate::parameters;
+use crate::reply_policy;
+use crate::settings_db;
+use crate::status;
+use teloxide::prelude::*;
+
+/// Shared state of the bot, which handlers with many dependencies get as a single one
+pub struct HandlerContext {
+    pub settings_db: std::sync::Arc<settings_db::SettingsDb>,
+    pub pool_factory: std::sync::Arc<db::SqliteDatabasePoolFactory>,
+    pub parameters: std::sync::Arc<parameters::Parameters>,
+    pub journal: std::sync::Arc<journal::ReplyJournal>,
+    pub limiter: std::sync::Arc<reply_policy::ReplyLimiter>,
+    pub error_reporter: std::sync::Arc<error_report::ErrorReporter>,
+    pub runtime: std::sync::Arc<status::RuntimeStatus>,
+    pub tasks: std::sync::Arc<background::BackgroundTasks>,
+}
+
+/// Builds the context from the dispatcher dependencies for the rest of the handler tree
+pub fn inject() -> teloxide::dispatching::UpdateHandler<anyhow::Error> {
+    dptree::map(
+        |settings_db: std::sync::Arc<settings_db::SettingsDb>,
+         pool_factory: std::sync::Arc<db::SqliteDatabasePoolFactory>,
+         parameters: std::sync::Arc<parameters::Parameters>,
+         journal: std::sync::Arc<journal::ReplyJournal>,
+         limiter: std::sync::Arc<reply_policy::ReplyLimiter>,
+         error_reporter: std::sync::Arc<error_report::ErrorReporter>,
+         runtime: std::sync::Arc<status::RuntimeStatus>,
+         tasks: std::sync::Arc<background::BackgroundTasks>| {
+            std::sync::Arc::new(HandlerContext {
+                settings_db,
+                pool_factory,
+                parameters,
+                journal,
+                limiter,
+                error_reporter,
+                runtime,
+                tasks,
+            })
+        },
+    )
+}
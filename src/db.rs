@@ -1,46 +1,565 @@
 use sqlx::sqlite::SqliteQueryResult;
 use sqlx::Error;
 
+// Every chat database tracks the number of applied migrations in `PRAGMA user_version`.
+// Migrations are only appended, never changed.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS forwarded_message (
+        message_id INTEGER PRIMARY KEY NOT NULL,
+        timestamp TIMESTAMP DEFAULT CURRENT_TIMESTAMP);",
+    "CREATE TABLE detection (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        message_id INTEGER NOT NULL,
+        user_id INTEGER,
+        detector TEXT NOT NULL,
+        reason TEXT NOT NULL,
+        is_shadow BOOLEAN NOT NULL,
+        timestamp TIMESTAMP DEFAULT CURRENT_TIMESTAMP);",
+    "ALTER TABLE detection ADD COLUMN suppressed_by TEXT;",
+    "CREATE TABLE fingerprint (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        detector TEXT NOT NULL,
+        value TEXT NOT NULL,
+        message_id INTEGER NOT NULL,
+        user_id INTEGER,
+        timestamp TIMESTAMP DEFAULT CURRENT_TIMESTAMP);
+    CREATE INDEX fingerprint_value ON fingerprint (detector, value);
+    INSERT INTO fingerprint (detector, value, message_id, timestamp)
+        SELECT 'forward', CAST(message_id AS TEXT), 0, timestamp FROM forwarded_message;
+    DROP TABLE forwarded_message;",
+    "ALTER TABLE detection ADD COLUMN fingerprint_id INTEGER;
+    CREATE TABLE false_positive (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        fingerprint_id INTEGER NOT NULL,
+        detection_id INTEGER NOT NULL,
+        user_id INTEGER,
+        timestamp TIMESTAMP DEFAULT CURRENT_TIMESTAMP);
+    CREATE INDEX false_positive_fingerprint ON false_positive (fingerprint_id);",
+    // Fingerprints expire sooner than detections, so the author of the original is kept separately
+    "ALTER TABLE detection ADD COLUMN original_user_id INTEGER;
+    UPDATE detection SET original_user_id = \
+        (SELECT user_id FROM fingerprint WHERE fingerprint.id = detection.fingerprint_id);",
+];
+
+pub const SCHEMA_VERSION: i64 = MIGRATIONS.len() as i64;
+
+// SQLite keeps these next to a database, depending on the journal mode
+const TEMPORARY_FILE_SUFFIXES: [&str; 3] = ["-wal", "-shm", "-journal"];
+
+// Value of `PRAGMA auto_vacuum`
+const INCREMENTAL_AUTO_VACUUM: i64 = 2;
+
+// Primary result codes of SQLite, which mean that the file itself is damaged
+const SQLITE_CORRUPT: i64 = 11;
+const SQLITE_NOTADB: i64 = 26;
+
+// Corrupt databases are moved there, inside the chat database directory
+const QUARANTINE_DIRECTORY: &str = "quarantine";
+
+// Tables recovered from a quarantined database, the ones referenced by others go first
+const SALVAGED_TABLES: [(&str, &str); 3] = [
+    (
+        "fingerprint",
+        "id, detector, value, message_id, user_id, timestamp",
+    ),
+    (
+        "detection",
+        "id, message_id, user_id, detector, reason, is_shadow, timestamp, suppressed_by, fingerprint_id, \
+        original_user_id",
+    ),
+    (
+        "false_positive",
+        "fingerprint_id, detection_id, user_id, timestamp",
+    ),
+];
+
+// Number of rows copied at once, when a table cannot be copied as a whole
+const SALVAGE_RANGE_SIZE: i64 = 100;
+
+/// A chat database, which was found corrupt and replaced with a fresh one
+pub struct QuarantineReport {
+    pub chat_id: i64,
+    pub path: std::path::PathBuf,
+    pub reason: String,
+    pub salvaged_rows: u64,
+}
+
 pub struct SqliteDatabasePoolFactory {
     db_root_path: std::path::PathBuf,
     max_connections_per_db: u32,
-    database_pools: std::collections::HashMap<i64, sqlx::SqlitePool>,
-    client_pool: std::collections::HashMap<i64, std::sync::Arc<ChatDatabase>>,
+    // Sharded map, so lookups for different chats don't contend with each other. The cell makes
+    // sure that concurrent first requests for the same chat open the database only once.
+    client_pool:
+        dashmap::DashMap<i64, std::sync::Arc<tokio::sync::OnceCell<std::sync::Arc<ChatDatabase>>>>,
+    // Set by the disk guard, when the free disk space runs out
+    is_read_only: std::sync::atomic::AtomicBool,
+    // Waiting to be reported to the owner
+    quarantine_reports: std::sync::Mutex<Vec<QuarantineReport>>,
+    // Databases checked for integrity when they were opened, and not verified since then
+    checked_on_open: dashmap::DashSet<i64>,
 }
 
 pub struct ChatDatabase {
     database_pool: sqlx::SqlitePool,
+    ordering_lock: tokio::sync::Mutex<()>,
 }
 
 impl ChatDatabase {
     pub fn new(database_pool: sqlx::SqlitePool) -> Self {
-        Self { database_pool }
+        Self {
+            database_pool,
+            ordering_lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Serializes check-then-modify sequences within a single chat.
+    /// The guard must not be held across Telegram API calls.
+    pub async fn lock_chat(&self) -> tokio::sync::MutexGuard<'_, ()> {
+        self.ordering_lock.lock().await
+    }
+
+    /// Returns the id of the stored fingerprint, if the same content was already posted in the
+    /// chat recently before `now`. Fingerprints, which were marked as false positives, are not
+    /// matched.
+    #[tracing::instrument(skip_all)]
+    pub async fn find_fingerprint(
+        &self,
+        fingerprint: &crate::detection::Fingerprint,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Option<i64>, Error> {
+        sqlx::query_scalar(
+            "SELECT id FROM fingerprint WHERE detector = ? AND value = ? AND timestamp >= date(?, '-1 day') \
+            AND id NOT IN (SELECT fingerprint_id FROM false_positive) ORDER BY id LIMIT 1",
+        )
+        .bind(fingerprint.detector.as_str())
+        .bind(&fingerprint.value)
+        .bind(sqlite_time(now))
+        .fetch_optional(&self.database_pool)
+        .await
+    }
+
+    /// Stores the fingerprint with the time of the message, the duplicate window is counted from it.
+    #[tracing::instrument(skip_all)]
+    pub async fn add_fingerprint(
+        &self,
+        fingerprint: &crate::detection::Fingerprint,
+        message_id: i32,
+        user_id: Option<u64>,
+        timestamp: chrono::DateTime<chrono::Utc>,
+    ) -> Result<SqliteQueryResult, Error> {
+        sqlx::query(
+            "INSERT INTO fingerprint (detector, value, message_id, user_id, timestamp) \
+            VALUES(?, ?, ?, ?, ?)",
+        )
+        .bind(fingerprint.detector.as_str())
+        .bind(&fingerprint.value)
+        .bind(message_id)
+        .bind(user_id.map(|user_id| user_id as i64))
+        .bind(sqlite_time(timestamp))
+        .execute(&self.database_pool)
+        .await
     }
 
-    pub async fn check_forward_message(&self, forward_message_id: &i32) -> Result<bool, Error> {
+    /// Removes fingerprints of the message, so its content is not matched anymore.
+    /// Returns the number of removed fingerprints.
+    #[tracing::instrument(skip_all)]
+    pub async fn remove_fingerprints(&self, message_id: i32) -> Result<u64, Error> {
+        let result = sqlx::query("DELETE FROM fingerprint WHERE message_id = ?")
+            .bind(message_id)
+            .execute(&self.database_pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub async fn add_detection(
+        &self,
+        message_id: i32,
+        user_id: Option<u64>,
+        reason: &crate::utils::SlowpokeReason,
+        is_shadow: bool,
+        suppressed_by: Option<String>,
+    ) -> Result<i64, Error> {
+        let fingerprint_id = match reason {
+            crate::utils::SlowpokeReason::Duplicate { fingerprint_id, .. } => Some(*fingerprint_id),
+            _ => None,
+        };
+
         let result = sqlx::query(
-            "SELECT message_id FROM forwarded_message WHERE message_id = ? AND timestamp >= date('now', '-1 day')",
+            "INSERT INTO detection (message_id, user_id, detector, reason, is_shadow, suppressed_by, fingerprint_id, \
+            original_user_id) VALUES(?, ?, ?, ?, ?, ?, ?, (SELECT user_id FROM fingerprint WHERE id = ?))",
         )
-        .bind(forward_message_id)
-        .fetch_optional(&self.database_pool).await?;
+        .bind(message_id)
+        .bind(user_id.map(|user_id| user_id as i64))
+        .bind(reason.detector())
+        .bind(reason.to_string())
+        .bind(is_shadow)
+        .bind(suppressed_by)
+        .bind(fingerprint_id)
+        .bind(fingerprint_id)
+        .execute(&self.database_pool)
+        .await?;
+
+        Ok(result.last_insert_rowid())
+    }
+
+    /// Excludes the fingerprint matched by the detection from further checks.
+    /// Returns false, if the detection is unknown.
+    #[tracing::instrument(skip_all)]
+    pub async fn add_false_positive(&self, detection_id: i64, user_id: u64) -> Result<bool, Error> {
+        let result = sqlx::query(
+            "INSERT INTO false_positive (fingerprint_id, detection_id, user_id) \
+            SELECT fingerprint_id, id, ? FROM detection WHERE id = ? AND fingerprint_id IS NOT NULL",
+        )
+        .bind(user_id as i64)
+        .bind(detection_id)
+        .execute(&self.database_pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub async fn has_detection(&self, message_id: i32) -> Result<bool, Error> {
+        let result = sqlx::query("SELECT id FROM detection WHERE message_id = ?")
+            .bind(message_id)
+            .fetch_optional(&self.database_pool)
+            .await?;
 
         Ok(result.is_some())
     }
 
-    pub async fn add_forwarded_message(
+    #[tracing::instrument(skip_all)]
+    pub async fn is_false_positive(&self, detection_id: i64) -> Result<bool, Error> {
+        let result = sqlx::query("SELECT id FROM false_positive WHERE detection_id = ?")
+            .bind(detection_id)
+            .fetch_optional(&self.database_pool)
+            .await?;
+
+        Ok(result.is_some())
+    }
+
+    /// Number of replies suppressed by the shadow mode per detector since `days` ago.
+    #[tracing::instrument(skip_all)]
+    pub async fn shadow_detection_counts(&self, days: i64) -> Result<Vec<(String, i64)>, Error> {
+        sqlx::query_as(
+            "SELECT detector, COUNT(*) FROM detection WHERE is_shadow AND timestamp >= datetime('now', ?) \
+            GROUP BY detector ORDER BY detector",
+        )
+        .bind(format!("-{} day", days))
+        .fetch_all(&self.database_pool)
+        .await
+    }
+
+    /// Returns message ids, reasons and times of the latest replies suppressed by the shadow mode.
+    #[tracing::instrument(skip_all)]
+    pub async fn recent_shadow_detections(
         &self,
-        forward_message_id: &i32,
-    ) -> Result<SqliteQueryResult, Error> {
-        sqlx::query("INSERT INTO forwarded_message (message_id) VALUES(?)")
-            .bind(forward_message_id)
-            .execute(&self.database_pool)
+        limit: i64,
+    ) -> Result<Vec<(i32, String, String)>, Error> {
+        sqlx::query_as(
+            "SELECT message_id, reason, timestamp FROM detection WHERE is_shadow ORDER BY id DESC LIMIT ?",
+        )
+        .bind(limit)
+        .fetch_all(&self.database_pool)
+        .await
+    }
+
+    /// Number of detected duplicates per detector, including the shadow ones, since `hours` ago.
+    #[tracing::instrument(skip_all)]
+    pub async fn detection_counts(&self, hours: i64) -> Result<Vec<(String, i64)>, Error> {
+        sqlx::query_as(
+            "SELECT detector, COUNT(*) FROM detection WHERE timestamp >= datetime('now', ?) \
+            GROUP BY detector ORDER BY detector",
+        )
+        .bind(format!("-{} hour", hours))
+        .fetch_all(&self.database_pool)
+        .await
+    }
+
+    /// Content, which was reposted most often since `days` ago: detector, fingerprint value if it
+    /// is still kept, id of the original or of the first repost, if it is known, and the number
+    /// of reposts.
+    #[tracing::instrument(skip_all)]
+    pub async fn most_reposted(
+        &self,
+        days: u32,
+        limit: i64,
+    ) -> Result<Vec<(String, Option<String>, Option<i32>, i64)>, Error> {
+        sqlx::query_as(
+            "SELECT detection.detector, fingerprint.value, \
+            COALESCE(NULLIF(fingerprint.message_id, 0), MIN(NULLIF(detection.message_id, 0))), COUNT(*) \
+            FROM detection LEFT JOIN fingerprint ON fingerprint.id = detection.fingerprint_id \
+            WHERE detection.fingerprint_id IS NOT NULL AND detection.timestamp >= datetime('now', ?) \
+            AND detection.id NOT IN (SELECT detection_id FROM false_positive) \
+            GROUP BY detection.fingerprint_id ORDER BY COUNT(*) DESC, MIN(detection.id) LIMIT ?",
+        )
+        .bind(format!("-{} day", days))
+        .bind(limit)
+        .fetch_all(&self.database_pool)
+        .await
+    }
+
+    /// Users, who reposted the most since `days` ago, with the number of their reposts.
+    #[tracing::instrument(skip_all)]
+    pub async fn top_reposters(&self, days: u32, limit: i64) -> Result<Vec<(i64, i64)>, Error> {
+        sqlx::query_as(
+            "SELECT user_id, COUNT(*) FROM detection \
+            WHERE user_id IS NOT NULL AND fingerprint_id IS NOT NULL AND timestamp >= datetime('now', ?) \
+            AND id NOT IN (SELECT detection_id FROM false_positive) \
+            GROUP BY user_id ORDER BY COUNT(*) DESC, MIN(id) LIMIT ?",
+        )
+        .bind(format!("-{} day", days))
+        .bind(limit)
+        .fetch_all(&self.database_pool)
+        .await
+    }
+
+    /// Users, whose posts were reposted by others since `days` ago, with the number of such posts.
+    #[tracing::instrument(skip_all)]
+    pub async fn top_original_posters(
+        &self,
+        days: u32,
+        limit: i64,
+    ) -> Result<Vec<(i64, i64)>, Error> {
+        sqlx::query_as(
+            "SELECT original_user_id, COUNT(DISTINCT fingerprint_id) FROM detection \
+            WHERE original_user_id IS NOT NULL AND original_user_id IS NOT user_id \
+            AND timestamp >= datetime('now', ?) AND id NOT IN (SELECT detection_id FROM false_positive) \
+            GROUP BY original_user_id ORDER BY COUNT(DISTINCT fingerprint_id) DESC, MIN(id) LIMIT ?",
+        )
+        .bind(format!("-{} day", days))
+        .bind(limit)
+        .fetch_all(&self.database_pool)
+        .await
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub async fn count_fingerprints(&self) -> Result<i64, Error> {
+        sqlx::query_scalar("SELECT COUNT(*) FROM fingerprint")
+            .fetch_one(&self.database_pool)
             .await
     }
 
-    pub async fn clean_old_messages(&self) -> Result<SqliteQueryResult, Error> {
-        sqlx::query("DELETE FROM forwarded_message WHERE timestamp < date('now', '-3 day');")
+    /// Returns detectors, values, message ids and times of the latest fingerprints, newest first.
+    #[tracing::instrument(skip_all)]
+    pub async fn recent_fingerprints(
+        &self,
+        limit: i64,
+    ) -> Result<Vec<(String, String, i32, String)>, Error> {
+        sqlx::query_as(
+            "SELECT detector, value, message_id, timestamp FROM fingerprint ORDER BY id DESC LIMIT ?",
+        )
+        .bind(limit)
+        .fetch_all(&self.database_pool)
+        .await
+    }
+
+    pub async fn close(&self) {
+        self.database_pool.close().await;
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub async fn schema_version(&self) -> Result<i64, Error> {
+        schema_version(&self.database_pool).await
+    }
+
+    /// Writes a transactionally consistent copy of the database, while it can still be used.
+    #[tracing::instrument(skip_all)]
+    pub async fn snapshot_into(&self, destination: &std::path::Path) -> anyhow::Result<()> {
+        let destination = destination
+            .to_str()
+            .ok_or_else(|| anyhow!("Cannot convert a snapshot path to a string"))?;
+
+        sqlx::query("VACUUM INTO ?")
+            .bind(destination)
             .execute(&self.database_pool)
-            .await
+            .await?;
+
+        Ok(())
+    }
+
+    /// Removes all records of the user. Returns the number of removed rows.
+    #[tracing::instrument(skip_all)]
+    pub async fn forget_user(&self, user_id: u64) -> Result<u64, Error> {
+        let mut transaction = self.database_pool.begin().await?;
+        let mut removed = 0;
+
+        for table in ["false_positive", "detection", "fingerprint"] {
+            removed += sqlx::query(&format!("DELETE FROM {} WHERE user_id = ?", table))
+                .bind(user_id as i64)
+                .execute(&mut transaction)
+                .await?
+                .rows_affected();
+        }
+        // Reposts of the user's messages belong to other users, so only the reference is removed
+        sqlx::query("UPDATE detection SET original_user_id = NULL WHERE original_user_id = ?")
+            .bind(user_id as i64)
+            .execute(&mut transaction)
+            .await?;
+
+        transaction.commit().await?;
+        Ok(removed)
+    }
+
+    /// Clears user ids of records older than `days`, the records themselves are kept.
+    #[tracing::instrument(skip_all)]
+    pub async fn remove_personal_data(&self, days: u32) -> Result<u64, Error> {
+        let mut transaction = self.database_pool.begin().await?;
+        let mut updated = 0;
+
+        for table in ["false_positive", "detection", "fingerprint"] {
+            updated += sqlx::query(&format!(
+                "UPDATE {} SET user_id = NULL WHERE user_id IS NOT NULL AND timestamp < datetime('now', ?)",
+                table
+            ))
+            .bind(format!("-{} day", days))
+            .execute(&mut transaction)
+            .await?
+            .rows_affected();
+        }
+        updated += sqlx::query(
+            "UPDATE detection SET original_user_id = NULL \
+            WHERE original_user_id IS NOT NULL AND timestamp < datetime('now', ?)",
+        )
+        .bind(format!("-{} day", days))
+        .execute(&mut transaction)
+        .await?
+        .rows_affected();
+
+        transaction.commit().await?;
+        Ok(updated)
+    }
+
+    /// Returns a description of the damage, if the database is corrupt.
+    #[tracing::instrument(skip_all)]
+    pub async fn find_corruption(&self) -> Result<Option<String>, Error> {
+        let result: Result<Vec<String>, Error> = sqlx::query_scalar("PRAGMA integrity_check")
+            .fetch_all(&self.database_pool)
+            .await;
+
+        match result {
+            Ok(lines) if lines.iter().all(|line| line == "ok") => Ok(None),
+            Ok(lines) => Ok(Some(lines.join("; "))),
+            Err(e) if is_corruption(&e) => Ok(Some(e.to_string())),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Copies the rows, which can still be read, from a corrupt chat database.
+    /// Returns the number of copied rows.
+    #[tracing::instrument(skip_all)]
+    pub async fn salvage_from(&self, path: &std::path::Path) -> anyhow::Result<u64> {
+        let path = path
+            .to_str()
+            .ok_or_else(|| anyhow!("Cannot convert a database path to a string"))?;
+
+        let mut connection = self.database_pool.acquire().await?;
+        sqlx::query("ATTACH DATABASE ? AS other")
+            .bind(path)
+            .execute(&mut connection)
+            .await?;
+
+        let mut salvaged = 0;
+        for (table, columns) in SALVAGED_TABLES {
+            salvaged += salvage_table(&mut connection, table, columns).await;
+        }
+
+        sqlx::query("DETACH DATABASE other")
+            .execute(&mut connection)
+            .await?;
+        Ok(salvaged)
+    }
+
+    /// Copies all rows of another chat database into this one. Row ids of the other database are
+    /// shifted, so references between its tables stay intact.
+    #[tracing::instrument(skip_all)]
+    pub async fn merge_from(&self, path: &std::path::Path) -> anyhow::Result<()> {
+        let path = path
+            .to_str()
+            .ok_or_else(|| anyhow!("Cannot convert a database path to a string"))?;
+
+        // Attached databases are per connection, so everything goes through one of them
+        let mut connection = self.database_pool.acquire().await?;
+        sqlx::query("ATTACH DATABASE ? AS other")
+            .bind(path)
+            .execute(&mut connection)
+            .await?;
+
+        let result = merge_attached(&mut connection).await;
+
+        sqlx::query("DETACH DATABASE other")
+            .execute(&mut connection)
+            .await?;
+        result
+    }
+
+    /// Removes fingerprints older than `max_age` together with false positives, which refer to them.
+    #[tracing::instrument(skip_all)]
+    pub async fn clean_old_messages(
+        &self,
+        max_age: std::time::Duration,
+    ) -> Result<SqliteQueryResult, Error> {
+        let mut transaction = self.database_pool.begin().await?;
+
+        let result = sqlx::query("DELETE FROM fingerprint WHERE timestamp < datetime('now', ?)")
+            .bind(format!("-{} seconds", max_age.as_secs()))
+            .execute(&mut transaction)
+            .await?;
+        sqlx::query(
+            "DELETE FROM false_positive WHERE fingerprint_id NOT IN (SELECT id FROM fingerprint)",
+        )
+        .execute(&mut transaction)
+        .await?;
+
+        transaction.commit().await?;
+        Ok(result)
+    }
+
+    /// Returns unix times of the oldest fingerprint and of the oldest record with a user id.
+    #[tracing::instrument(skip_all)]
+    pub async fn oldest_record_times(&self) -> Result<(Option<i64>, Option<i64>), Error> {
+        let fingerprint = sqlx::query_scalar(
+            "SELECT CAST(strftime('%s', MIN(timestamp)) AS INTEGER) FROM fingerprint",
+        )
+        .fetch_one(&self.database_pool)
+        .await?;
+
+        let personal_data = sqlx::query_scalar(
+            "SELECT CAST(strftime('%s', MIN(timestamp)) AS INTEGER) FROM ( \
+            SELECT timestamp FROM fingerprint WHERE user_id IS NOT NULL \
+            UNION ALL SELECT timestamp FROM detection WHERE user_id IS NOT NULL OR original_user_id IS NOT NULL \
+            UNION ALL SELECT timestamp FROM false_positive WHERE user_id IS NOT NULL)",
+        )
+        .fetch_one(&self.database_pool)
+        .await?;
+
+        Ok((fingerprint, personal_data))
+    }
+
+    /// Returns free pages to the file system. Databases created before incremental vacuum was
+    /// enabled are rebuilt once, which needs as much free space as the database takes, so
+    /// `allow_rebuild` has to be false when the disk is almost full.
+    #[tracing::instrument(skip_all)]
+    pub async fn vacuum(&self, allow_rebuild: bool) -> Result<(), Error> {
+        let mut connection = self.database_pool.acquire().await?;
+
+        let auto_vacuum: i64 = sqlx::query_scalar("PRAGMA auto_vacuum")
+            .fetch_one(&mut connection)
+            .await?;
+        if auto_vacuum == INCREMENTAL_AUTO_VACUUM {
+            sqlx::query("PRAGMA incremental_vacuum")
+                .execute(&mut connection)
+                .await?;
+        } else if allow_rebuild {
+            sqlx::query("PRAGMA auto_vacuum = INCREMENTAL")
+                .execute(&mut connection)
+                .await?;
+            sqlx::query("VACUUM").execute(&mut connection).await?;
+        }
+
+        Ok(())
     }
 }
 
@@ -49,13 +568,76 @@ impl SqliteDatabasePoolFactory {
         Self {
             db_root_path,
             max_connections_per_db,
-            database_pools: Default::default(),
             client_pool: Default::default(),
+            is_read_only: Default::default(),
+            quarantine_reports: Default::default(),
+            checked_on_open: Default::default(),
+        }
+    }
+
+    pub fn take_quarantine_reports(&self) -> Vec<QuarantineReport> {
+        std::mem::take(
+            &mut *self
+                .quarantine_reports
+                .lock()
+                .expect("Quarantine reports mutex is poisoned"),
+        )
+    }
+
+    /// Checks the chat database and quarantines it, if it is corrupt.
+    /// Returns false in that case.
+    pub async fn verify_chat(&self, chat_id: i64) -> anyhow::Result<bool> {
+        let client = self.create(chat_id).await?;
+        // Opening checks the whole database, there is no need to repeat it right away
+        if self.checked_on_open.remove(&chat_id).is_some() {
+            return Ok(true);
         }
+        let corruption = match client.find_corruption().await? {
+            Some(corruption) => corruption,
+            None => return Ok(true),
+        };
+
+        log::error!(
+            "The database of the chat {} is corrupt: {}",
+            chat_id,
+            corruption
+        );
+        self.evict(chat_id).await;
+        // Opening checks the database again and quarantines it
+        self.create(chat_id).await?;
+
+        Ok(false)
     }
 
-    pub fn list_existing_chats(&self) -> std::vec::Vec<i64> {
-        let chat_paths = std::fs::read_dir(self.db_root_path.as_path()).unwrap();
+    /// Number of chat databases, which are open now
+    pub fn open_pool_count(&self) -> usize {
+        self.client_pool
+            .iter()
+            .filter(|entry| entry.value().initialized())
+            .count()
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.is_read_only.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// New messages are not recorded and no new chat databases are created in the read-only mode.
+    pub fn set_read_only(&self, is_read_only: bool) {
+        self.is_read_only
+            .store(is_read_only, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    pub fn list_existing_chats(&self) -> anyhow::Result<std::vec::Vec<i64>> {
+        let chat_paths = match std::fs::read_dir(self.db_root_path.as_path()) {
+            Ok(chat_paths) => chat_paths,
+            // Nothing was stored yet
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => bail!(
+                "Cannot read the chat database directory {}: {}",
+                self.db_root_path.display(),
+                e
+            ),
+        };
 
         let mut chats = Vec::new();
 
@@ -88,49 +670,557 @@ impl SqliteDatabasePoolFactory {
             }
         }
 
-        chats
+        Ok(chats)
     }
 
-    pub async fn init_new_db(&self, db: sqlx::SqlitePool) -> Result<SqliteQueryResult, Error> {
-        sqlx::query(
-            "CREATE TABLE IF NOT EXISTS forwarded_message (
-                message_id INTEGER PRIMARY KEY NOT NULL,
-                timestamp TIMESTAMP DEFAULT CURRENT_TIMESTAMP);",
-        )
-        .execute(&db)
+    /// Removes SQLite temporary files left without their databases, e.g. after a crash during
+    /// a purge. Returns the removed paths.
+    pub fn remove_stale_files(&self) -> anyhow::Result<Vec<std::path::PathBuf>> {
+        let mut removed = Vec::new();
+
+        for entry in std::fs::read_dir(self.db_root_path.as_path())? {
+            let path = entry?.path();
+            let file_name = match path.file_name().and_then(|name| name.to_str()) {
+                Some(file_name) => file_name,
+                None => continue,
+            };
+            let database_name = match TEMPORARY_FILE_SUFFIXES
+                .iter()
+                .find_map(|suffix| file_name.strip_suffix(suffix))
+            {
+                Some(database_name) => database_name,
+                None => continue,
+            };
+
+            if !database_name.ends_with(".db") || path.with_file_name(database_name).exists() {
+                continue;
+            }
+            std::fs::remove_file(&path)?;
+            removed.push(path);
+        }
+
+        Ok(removed)
+    }
+
+    /// Returns the time of the last change of the chat database, including its write-ahead log.
+    pub fn database_modified_at(&self, chat_id: i64) -> Option<std::time::SystemTime> {
+        let db_path = self.database_path(chat_id);
+        ["", "-wal"]
+            .iter()
+            .filter_map(|suffix| {
+                let mut path = db_path.clone().into_os_string();
+                path.push(suffix);
+                std::fs::metadata(path)
+                    .and_then(|metadata| metadata.modified())
+                    .ok()
+            })
+            .max()
+    }
+
+    pub async fn init_new_db(&self, db: sqlx::SqlitePool) -> anyhow::Result<()> {
+        let current_version = schema_version(&db).await?;
+
+        if current_version > SCHEMA_VERSION {
+            bail!(
+                "Database schema version {} is newer than the supported one {}",
+                current_version,
+                SCHEMA_VERSION
+            );
+        }
+
+        for (version, migration) in MIGRATIONS.iter().enumerate().skip(current_version as usize) {
+            let mut transaction = db.begin().await?;
+            sqlx::query(migration).execute(&mut transaction).await?;
+            sqlx::query(&format!("PRAGMA user_version = {}", version + 1))
+                .execute(&mut transaction)
+                .await?;
+            transaction.commit().await?;
+        }
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub async fn create(&self, chat_id: i64) -> anyhow::Result<std::sync::Arc<ChatDatabase>> {
+        // Clone the cell out of the map, so the shard lock is released before any I/O
+        let cell = self.client_pool.entry(chat_id).or_default().clone();
+
+        let client = cell.get_or_try_init(|| self.open(chat_id)).await?;
+
+        Ok(client.clone())
+    }
+
+    /// Opens the chat database only for reading. Unlike `create`, migrations and the integrity
+    /// check are not applied and the database is not kept open, so inspection never changes it.
+    pub async fn open_read_only(&self, chat_id: i64) -> anyhow::Result<ChatDatabase> {
+        let connection_options = sqlx::sqlite::SqliteConnectOptions::default()
+            .filename(self.database_path(chat_id))
+            .read_only(true);
+
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(connection_options)
+            .await?;
+
+        Ok(ChatDatabase::new(pool))
+    }
+
+    pub fn database_path(&self, chat_id: i64) -> std::path::PathBuf {
+        self.db_root_path.join(format!("{}.db", chat_id))
+    }
+
+    /// Closes the chat database and removes it together with SQLite temporary files.
+    pub async fn purge(&self, chat_id: i64) -> anyhow::Result<()> {
+        self.evict(chat_id).await;
+        self.remove_files(chat_id)
+    }
+
+    /// Moves all data of the old chat into the new one, when a group becomes a supergroup.
+    /// Data, which the new chat already has, is kept. Returns false, if there was nothing to move.
+    pub async fn migrate_chat(&self, old_chat_id: i64, new_chat_id: i64) -> anyhow::Result<bool> {
+        let new_client = self.create(new_chat_id).await?;
+        let _chat_guard = new_client.lock_chat().await;
+
+        // Both the old and the new chat get a service message, the second one finds nothing
+        if !self.database_path(old_chat_id).exists() {
+            return Ok(false);
+        }
+
+        // Opening applies pending migrations, so both databases have the same schema
+        self.create(old_chat_id).await?;
+        self.evict(old_chat_id).await;
+
+        new_client
+            .merge_from(&self.database_path(old_chat_id))
+            .await?;
+        self.remove_files(old_chat_id)?;
+
+        Ok(true)
+    }
+
+    async fn evict(&self, chat_id: i64) {
+        if let Some((_, cell)) = self.client_pool.remove(&chat_id) {
+            if let Some(client) = cell.get() {
+                client.close().await;
+            }
+        }
+    }
+
+    fn remove_files(&self, chat_id: i64) -> anyhow::Result<()> {
+        let db_path = self.database_path(chat_id);
+        for suffix in std::iter::once("").chain(TEMPORARY_FILE_SUFFIXES) {
+            let mut path = db_path.clone().into_os_string();
+            path.push(suffix);
+            match std::fs::remove_file(&path) {
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn open(&self, chat_id: i64) -> anyhow::Result<std::sync::Arc<ChatDatabase>> {
+        let corruption = match self.connect(chat_id).await {
+            Ok(client) => match client.find_corruption().await? {
+                Some(corruption) => {
+                    client.close().await;
+                    corruption
+                }
+                None => {
+                    self.checked_on_open.insert(chat_id);
+                    return Ok(client);
+                }
+            },
+            Err(e) if e.downcast_ref().is_some_and(is_corruption) => e.to_string(),
+            Err(e) => return Err(e),
+        };
+
+        self.quarantine(chat_id, corruption).await
+    }
+
+    // The corrupt files are kept for investigation, the chat continues with a fresh database
+    async fn quarantine(
+        &self,
+        chat_id: i64,
+        reason: String,
+    ) -> anyhow::Result<std::sync::Arc<ChatDatabase>> {
+        let quarantine_root = self.db_root_path.join(QUARANTINE_DIRECTORY);
+        std::fs::create_dir_all(&quarantine_root)?;
+        let quarantine_path =
+            quarantine_root.join(format!("{}-{}.db", chat_id, chrono::Utc::now().timestamp()));
+
+        let db_path = self.database_path(chat_id);
+        for suffix in std::iter::once("").chain(TEMPORARY_FILE_SUFFIXES) {
+            let mut source = db_path.clone().into_os_string();
+            source.push(suffix);
+            let mut destination = quarantine_path.clone().into_os_string();
+            destination.push(suffix);
+            match std::fs::rename(&source, &destination) {
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let client = self.connect(chat_id).await?;
+        let salvaged_rows = match client.salvage_from(&quarantine_path).await {
+            Ok(salvaged_rows) => salvaged_rows,
+            Err(e) => {
+                log::warn!("Cannot read the quarantined database: {:?}", e);
+                0
+            }
+        };
+        log::error!(
+            "The database of the chat {} is corrupt and was moved to {}, {} rows were recovered: {}",
+            chat_id,
+            quarantine_path.display(),
+            salvaged_rows,
+            reason
+        );
+
+        self.quarantine_reports
+            .lock()
+            .expect("Quarantine reports mutex is poisoned")
+            .push(QuarantineReport {
+                chat_id,
+                path: quarantine_path,
+                reason,
+                salvaged_rows,
+            });
+
+        Ok(client)
+    }
+
+    async fn connect(&self, chat_id: i64) -> anyhow::Result<std::sync::Arc<ChatDatabase>> {
+        let new_db_path = self.database_path(chat_id);
+
+        let connection_string = (new_db_path
+            .to_str()
+            .ok_or_else(|| anyhow!("Cannot convert a database path to a string"))?)
+        .to_string();
+
+        log::info!("{}", connection_string);
+
+        if self.is_read_only() && !new_db_path.exists() {
+            bail!(
+                "Not enough disk space to create a database for the chat {}",
+                chat_id
+            );
+        }
+
+        // Applies only to new databases, existing ones are converted by the maintenance
+        let connection_options = sqlx::sqlite::SqliteConnectOptions::default()
+            .create_if_missing(true)
+            .auto_vacuum(sqlx::sqlite::SqliteAutoVacuum::Incremental)
+            .filename(connection_string);
+
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(self.max_connections_per_db)
+            .connect_with(connection_options)
+            .await?;
+
+        self.init_new_db(pool.clone()).await?;
+
+        Ok(std::sync::Arc::new(ChatDatabase::new(pool)))
+    }
+}
+
+// Same format as CURRENT_TIMESTAMP, so the stored times are compared as strings
+fn sqlite_time(time: chrono::DateTime<chrono::Utc>) -> String {
+    time.format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+fn is_corruption(error: &Error) -> bool {
+    let code = match error {
+        Error::Database(error) => error.code().and_then(|code| code.parse::<i64>().ok()),
+        _ => None,
+    };
+
+    // Extended result codes keep the primary one in the lowest byte
+    code.is_some_and(|code| matches!(code & 0xff, SQLITE_CORRUPT | SQLITE_NOTADB))
+}
+
+// A damaged page fails the whole statement, so the table is copied again in small rowid ranges
+// to keep the rows outside of it
+async fn salvage_table(connection: &mut sqlx::SqliteConnection, table: &str, columns: &str) -> u64 {
+    let copy = format!(
+        "INSERT OR IGNORE INTO main.{} ({}) SELECT {} FROM other.{}",
+        table, columns, columns, table
+    );
+    match sqlx::query(&copy).execute(&mut *connection).await {
+        Ok(result) => return result.rows_affected(),
+        Err(e) => log::warn!("Cannot copy the table {} as a whole: {}", table, e),
+    }
+
+    let max_rowid: i64 = match sqlx::query_scalar(&format!(
+        "SELECT IFNULL(MAX(rowid), 0) FROM other.{}",
+        table
+    ))
+    .fetch_one(&mut *connection)
+    .await
+    {
+        Ok(max_rowid) => max_rowid,
+        Err(e) => {
+            log::warn!("Cannot read the table {}: {}", table, e);
+            return 0;
+        }
+    };
+
+    let copy_range = format!("{} WHERE rowid BETWEEN ? AND ?", copy);
+    let mut salvaged = 0;
+    for start in (1..=max_rowid).step_by(SALVAGE_RANGE_SIZE as usize) {
+        match sqlx::query(&copy_range)
+            .bind(start)
+            .bind(start + SALVAGE_RANGE_SIZE - 1)
+            .execute(&mut *connection)
+            .await
+        {
+            Ok(result) => salvaged += result.rows_affected(),
+            Err(e) => log::debug!("Rows of {} from {} are lost: {}", table, start, e),
+        }
+    }
+
+    salvaged
+}
+
+// Column lists have to follow the schema, when tables change. Message ids start anew in the new
+// chat, so the merged rows get 0 instead, like the rows converted by the fourth migration.
+async fn merge_attached(connection: &mut sqlx::SqliteConnection) -> anyhow::Result<()> {
+    use sqlx::Connection;
+
+    let mut transaction = connection.begin().await?;
+
+    let fingerprint_offset: i64 =
+        sqlx::query_scalar("SELECT IFNULL(MAX(id), 0) FROM main.fingerprint")
+            .fetch_one(&mut transaction)
+            .await?;
+    let detection_offset: i64 = sqlx::query_scalar("SELECT IFNULL(MAX(id), 0) FROM main.detection")
+        .fetch_one(&mut transaction)
+        .await?;
+
+    sqlx::query(
+        "INSERT INTO main.fingerprint (id, detector, value, message_id, user_id, timestamp) \
+        SELECT id + ?, detector, value, 0, user_id, timestamp FROM other.fingerprint",
+    )
+    .bind(fingerprint_offset)
+    .execute(&mut transaction)
+    .await?;
+    sqlx::query(
+        "INSERT INTO main.detection (id, message_id, user_id, detector, reason, is_shadow, timestamp, \
+        suppressed_by, fingerprint_id, original_user_id) \
+        SELECT id + ?, 0, user_id, detector, reason, is_shadow, timestamp, suppressed_by, \
+        fingerprint_id + ?, original_user_id FROM other.detection",
+    )
+    .bind(detection_offset)
+    .bind(fingerprint_offset)
+    .execute(&mut transaction)
+    .await?;
+    sqlx::query(
+        "INSERT INTO main.false_positive (fingerprint_id, detection_id, user_id, timestamp) \
+        SELECT fingerprint_id + ?, detection_id + ?, user_id, timestamp FROM other.false_positive",
+    )
+    .bind(fingerprint_offset)
+    .bind(detection_offset)
+    .execute(&mut transaction)
+    .await?;
+
+    transaction.commit().await?;
+    Ok(())
+}
+
+async fn schema_version(db: &sqlx::SqlitePool) -> Result<i64, Error> {
+    sqlx::query_scalar("PRAGMA user_version")
+        .fetch_one(db)
         .await
+}
+
+/// Checks a standalone chat database file, which is not managed by the factory.
+/// Returns the schema version of the database.
+pub async fn verify_database_file(path: &std::path::Path) -> anyhow::Result<i64> {
+    let connection_options = sqlx::sqlite::SqliteConnectOptions::default()
+        .filename(path)
+        .read_only(true);
+
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(connection_options)
+        .await?;
+
+    let integrity: String = sqlx::query_scalar("PRAGMA integrity_check")
+        .fetch_one(&pool)
+        .await?;
+    if integrity != "ok" {
+        bail!("Integrity check failed: {}", integrity);
     }
 
-    pub async fn create(&mut self, chat_id: i64) -> anyhow::Result<std::sync::Arc<ChatDatabase>> {
-        if let Some(client) = self.client_pool.get(&chat_id) {
-            Ok(client.clone())
-        } else {
-            let new_db_path = self.db_root_path.join(format!("{}.db", chat_id));
+    let version = schema_version(&pool).await?;
+    pool.close().await;
 
-            let connection_string = (new_db_path
-                .to_str()
-                .ok_or_else(|| anyhow!("Cannot convert a database path to a string"))?)
-            .to_string();
+    if version > SCHEMA_VERSION {
+        bail!(
+            "Database schema version {} is newer than the supported one {}",
+            version,
+            SCHEMA_VERSION
+        );
+    }
 
-            log::info!("{}", connection_string);
+    Ok(version)
+}
 
-            let connection_options = sqlx::sqlite::SqliteConnectOptions::default()
-                .create_if_missing(true)
-                .filename(connection_string);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detection::{Detector, Fingerprint};
 
-            let pool = sqlx::sqlite::SqlitePoolOptions::new()
-                .max_connections(self.max_connections_per_db)
-                .connect_with(connection_options)
-                .await?;
+    fn fingerprint(value: &str) -> Fingerprint {
+        Fingerprint {
+            detector: Detector::Link,
+            value: value.to_string(),
+        }
+    }
 
-            self.init_new_db(pool.clone()).await?;
+    #[tokio::test]
+    async fn migrated_chat_keeps_fingerprints_and_detections() {
+        let root = tempfile::tempdir().unwrap();
+        let pool_factory = SqliteDatabasePoolFactory::new(root.path().to_path_buf(), 1);
 
-            self.database_pools.insert(chat_id, pool.clone());
+        let old_client = pool_factory.create(-1).await.unwrap();
+        old_client
+            .add_fingerprint(
+                &fingerprint("example.com/a"),
+                10,
+                Some(1),
+                chrono::Utc::now(),
+            )
+            .await
+            .unwrap();
+        let original_id = old_client
+            .find_fingerprint(&fingerprint("example.com/a"), chrono::Utc::now())
+            .await
+            .unwrap()
+            .unwrap();
+        let reason = crate::utils::SlowpokeReason::Duplicate {
+            fingerprint: fingerprint("example.com/a"),
+            fingerprint_id: original_id,
+        };
+        old_client
+            .add_detection(11, Some(2), &reason, false, None)
+            .await
+            .unwrap();
 
-            let new_client = std::sync::Arc::new(ChatDatabase::new(pool));
-            self.client_pool.insert(chat_id, new_client.clone());
+        // The new chat already has data, so the merged row ids are shifted
+        let new_client = pool_factory.create(-1001).await.unwrap();
+        new_client
+            .add_fingerprint(
+                &fingerprint("example.com/b"),
+                20,
+                Some(3),
+                chrono::Utc::now(),
+            )
+            .await
+            .unwrap();
 
-            Ok(new_client)
+        assert!(pool_factory.migrate_chat(-1, -1001).await.unwrap());
+        assert!(!pool_factory.database_path(-1).exists());
+
+        let fingerprints: Vec<(String, i64, Option<i64>)> =
+            sqlx::query_as("SELECT value, message_id, user_id FROM fingerprint ORDER BY id")
+                .fetch_all(&new_client.database_pool)
+                .await
+                .unwrap();
+        assert_eq!(
+            fingerprints,
+            vec![
+                ("example.com/b".to_string(), 20, Some(3)),
+                ("example.com/a".to_string(), 0, Some(1)),
+            ]
+        );
+
+        let detections: Vec<(i64, Option<i64>, Option<i64>, String)> = sqlx::query_as(
+            "SELECT detection.message_id, detection.user_id, detection.original_user_id, \
+            fingerprint.value FROM detection JOIN fingerprint ON fingerprint.id = detection.fingerprint_id",
+        )
+        .fetch_all(&new_client.database_pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            detections,
+            vec![(0, Some(2), Some(1), "example.com/a".to_string())]
+        );
+
+        // Moved fingerprints are still matched in the new chat
+        assert!(new_client
+            .find_fingerprint(&fingerprint("example.com/a"), chrono::Utc::now())
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    // Breaks the first leaf page of the fingerprint table, which holds the value
+    fn damage_page_with(path: &std::path::Path, value: &str) {
+        const PAGE_SIZE: usize = 4096;
+        // Type of the b-tree page, which keeps rows of a table
+        const TABLE_LEAF_PAGE: u8 = 0x0d;
+
+        let mut bytes = std::fs::read(path).unwrap();
+        let page = bytes
+            .chunks(PAGE_SIZE)
+            .position(|page| {
+                page[0] == TABLE_LEAF_PAGE
+                    && page
+                        .windows(value.len())
+                        .any(|window| window == value.as_bytes())
+            })
+            .expect("No page with the value");
+        bytes[page * PAGE_SIZE..page * PAGE_SIZE + 8].fill(0xff);
+        std::fs::write(path, bytes).unwrap();
+    }
+
+    #[tokio::test]
+    async fn readable_rows_of_a_corrupt_database_are_salvaged() {
+        const ROWS: u64 = 1000;
+
+        let root = tempfile::tempdir().unwrap();
+        let pool_factory = SqliteDatabasePoolFactory::new(root.path().to_path_buf(), 1);
+        let client = pool_factory.create(-1).await.unwrap();
+        for index in 0..ROWS {
+            let value = format!("example.com/{}/{}", index, "a".repeat(100));
+            client
+                .add_fingerprint(
+                    &fingerprint(&value),
+                    index as i32,
+                    Some(1),
+                    chrono::Utc::now(),
+                )
+                .await
+                .unwrap();
         }
+        // Closing the last connection moves the write-ahead log into the database file
+        drop(client);
+        pool_factory.evict(-1).await;
+        damage_page_with(&pool_factory.database_path(-1), "example.com/500/");
+
+        let pool_factory = SqliteDatabasePoolFactory::new(root.path().to_path_buf(), 1);
+        let client = pool_factory.create(-1).await.unwrap();
+
+        let reports = pool_factory.take_quarantine_reports();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].chat_id, -1);
+        assert!(reports[0]
+            .path
+            .starts_with(root.path().join(QUARANTINE_DIRECTORY)));
+        assert!(reports[0].path.exists());
+
+        // Only the rows on the damaged page and in its copied range are lost
+        let salvaged = reports[0].salvaged_rows;
+        assert!(salvaged >= ROWS - SALVAGE_RANGE_SIZE as u64 * 2 && salvaged < ROWS);
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM fingerprint")
+            .fetch_one(&client.database_pool)
+            .await
+            .unwrap();
+        assert_eq!(count as u64, salvaged);
+        assert_eq!(client.find_corruption().await.unwrap(), None);
     }
 }
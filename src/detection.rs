@@ -0,0 +1,687 @@
+use crate::channel;
+use crate::context;
+use crate::db;
+use crate::logging::TracedRequest;
+use crate::moderation;
+use crate::privacy;
+use crate::settings;
+use crate::settings_db;
+use crate::utils;
+use sha2::Digest;
+use teloxide::prelude::*;
+use teloxide::types::{MessageEntity, MessageEntityKind};
+
+// Short texts like greetings repeat all the time and are not worth a slowpoke
+const MIN_TEXT_LENGTH: usize = 50;
+
+// Query parameters, which only track where a link was clicked and don't change the content
+static TRACKING_PARAMETER_PREFIXES: &[&str] = &["utm_", "fbclid", "gclid", "yclid"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Detector {
+    Forward,
+    Link,
+    Text,
+    Media,
+}
+
+impl Detector {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Detector::Forward => "forward",
+            Detector::Link => "link",
+            Detector::Text => "text",
+            Detector::Media => "media",
+        }
+    }
+}
+
+/// Identity of a message content, messages with equal fingerprints are duplicates
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Fingerprint {
+    pub detector: Detector,
+    pub value: String,
+}
+
+/// Returns fingerprints of the message, which have to be checked for duplicates, or nothing if
+/// the message has to be ignored. Exemptions are applied here, so exempt messages never reach
+/// the chat database. The list can be empty, when the message has no checked content.
+pub fn fingerprints_to_check(
+    msg: &Message,
+    settings_db: &settings_db::SettingsDb,
+) -> Option<Vec<Fingerprint>> {
+    let exemptions = match settings_db
+        .get::<settings::Exemptions>(settings_db::Namespace::Chat(msg.chat.id.0))
+    {
+        Ok(exemptions) => exemptions,
+        Err(e) => {
+            log::warn!("Cannot get exemptions of the chat {}: {:?}", msg.chat.id, e);
+            Default::default()
+        }
+    };
+
+    if let Some(user) = msg.from() {
+        // Edits still get an empty list, so fingerprints of the original version are removed
+        let ignored = msg.edit_date().map(|_| Vec::new());
+        if privacy::is_opted_out(settings_db, user.id.0) {
+            return ignored;
+        }
+        if exemptions.users.contains(&user.id.0) {
+            log::debug!("The user {} is exempt in the chat {}", user.id, msg.chat.id);
+            return ignored;
+        }
+    }
+
+    let (linked_channel_mode, linked_channel_id) = match linked_channel(msg, settings_db) {
+        Ok(linked_channel) => linked_channel,
+        Err(e) => {
+            log::warn!(
+                "Cannot get the linked channel of the chat {}: {:?}",
+                msg.chat.id,
+                e
+            );
+            Default::default()
+        }
+    };
+    if msg.is_automatic_forward() && linked_channel_mode != settings::LinkedChannelMode::Record {
+        return None;
+    }
+
+    // Forwards from exempt sources are skipped as a whole, otherwise their text and media
+    // would still be checked
+    let source = msg.forward_from_chat().map(|chat| chat.id.0);
+    if let Some(source) = source {
+        let is_exempt_linked_channel = linked_channel_mode == settings::LinkedChannelMode::Exempt
+            && linked_channel_id == Some(source);
+        if exemptions.forward_sources.contains(&source) || is_exempt_linked_channel {
+            log::debug!("The forward source is exempt in the chat {}", msg.chat.id);
+            return None;
+        }
+    }
+
+    let mut fingerprints = Vec::new();
+
+    // Message ids are counted per channel, so the channel is a part of the value
+    if let (Some(source), Some(forward_from_message_id)) = (source, msg.forward_from_message_id()) {
+        fingerprints.push(Fingerprint {
+            detector: Detector::Forward,
+            value: format!("{}/{}", source, forward_from_message_id),
+        });
+    }
+
+    for link in extract_links(msg) {
+        let url = match normalize_link(&link) {
+            Some(url) => url,
+            None => continue,
+        };
+        let host = url.host_str().unwrap_or_default();
+        if exemptions.is_domain_exempt(host) {
+            continue;
+        }
+
+        let fingerprint = Fingerprint {
+            detector: Detector::Link,
+            value: link_value(&url),
+        };
+        if !fingerprints.contains(&fingerprint) {
+            fingerprints.push(fingerprint);
+        }
+    }
+
+    if let Some(value) = text_value(msg) {
+        fingerprints.push(Fingerprint {
+            detector: Detector::Text,
+            value,
+        });
+    }
+
+    if let Some(value) = media_value(msg) {
+        fingerprints.push(Fingerprint {
+            detector: Detector::Media,
+            value,
+        });
+    }
+
+    Some(fingerprints)
+}
+
+// Texts are compared regardless of case, punctuation and spacing. Only a hash is stored.
+fn text_value(msg: &Message) -> Option<String> {
+    let text = msg.text().or_else(|| msg.caption())?;
+
+    let normalized = text
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+    if normalized.chars().count() < MIN_TEXT_LENGTH {
+        return None;
+    }
+
+    Some(format!("{:x}", sha2::Sha256::digest(normalized.as_bytes())))
+}
+
+// Telegram keeps the same unique id for the same file, e.g. when it is forwarded or resent.
+// Stickers are skipped, since the same sticker is used over and over again.
+fn media_value(msg: &Message) -> Option<String> {
+    let file_unique_id = if let Some(photo) = msg.photo() {
+        // Sizes of the same photo have different ids, the largest one is the last
+        &photo.last()?.file_unique_id
+    } else if let Some(video) = msg.video() {
+        &video.file_unique_id
+    } else if let Some(animation) = msg.animation() {
+        &animation.file_unique_id
+    } else if let Some(document) = msg.document() {
+        &document.file_unique_id
+    } else if let Some(audio) = msg.audio() {
+        &audio.file_unique_id
+    } else {
+        return None;
+    };
+
+    Some(file_unique_id.clone())
+}
+
+// Automatic forwards come only from the linked channel, so its id is remembered from them
+fn linked_channel(
+    msg: &Message,
+    settings_db: &settings_db::SettingsDb,
+) -> anyhow::Result<(settings::LinkedChannelMode, Option<i64>)> {
+    let namespace = settings_db::Namespace::Chat(msg.chat.id.0);
+    let mode = settings_db.get::<settings::LinkedChannel>(namespace)?;
+    let mut channel_id = settings_db.get::<settings::LinkedChannelId>(namespace)?;
+
+    if msg.is_automatic_forward() {
+        let source = msg.forward_from_chat().map(|chat| chat.id.0);
+        if source.is_some() && channel_id != source {
+            channel_id = source;
+            settings_db.set::<settings::LinkedChannelId>(namespace, &channel_id)?;
+        }
+    }
+
+    Ok((mode, channel_id))
+}
+
+fn extract_links(msg: &Message) -> Vec<String> {
+    let mut links = Vec::new();
+
+    let parts = [
+        (msg.text(), msg.entities()),
+        (msg.caption(), msg.caption_entities()),
+    ];
+    for (text, entities) in parts {
+        let (text, entities) = match (text, entities) {
+            (Some(text), Some(entities)) => (text, entities),
+            _ => continue,
+        };
+
+        for entity in entities {
+            match &entity.kind {
+                MessageEntityKind::Url => {
+                    if let Some(link) = entity_text(text, entity) {
+                        links.push(link);
+                    }
+                }
+                MessageEntityKind::TextLink { url } => links.push(url.to_string()),
+                _ => {}
+            }
+        }
+    }
+
+    links
+}
+
+// Entity offsets are measured in UTF-16 code units
+fn entity_text(text: &str, entity: &MessageEntity) -> Option<String> {
+    let utf16: Vec<u16> = text.encode_utf16().collect();
+    let part = utf16.get(entity.offset..entity.offset + entity.length)?;
+    String::from_utf16(part).ok()
+}
+
+fn normalize_link(link: &str) -> Option<url::Url> {
+    // Telegram recognizes links without a scheme, e.g. "example.com/page". A scheme has no dots,
+    // unlike a host followed by a port.
+    let has_scheme = link.split_once(':').is_some_and(|(scheme, _)| {
+        !scheme.is_empty()
+            && scheme
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-')
+    });
+    let url = if has_scheme {
+        url::Url::parse(link)
+    } else {
+        url::Url::parse(&format!("http://{}", link))
+    }
+    .ok()?;
+
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return None;
+    }
+    url.host_str()?;
+
+    Some(url)
+}
+
+// The same page is often shared with a different scheme, "www." or tracking parameters
+fn link_value(url: &url::Url) -> String {
+    let host = url.host_str().unwrap_or_default();
+    let host = host.strip_prefix("www.").unwrap_or(host);
+
+    let query: Vec<String> = url
+        .query_pairs()
+        .filter(|(name, _)| {
+            !TRACKING_PARAMETER_PREFIXES
+                .iter()
+                .any(|prefix| name.starts_with(prefix))
+        })
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect();
+
+    let mut value = format!("{}{}", host, url.path().trim_end_matches('/'));
+    if !query.is_empty() {
+        value.push('?');
+        value.push_str(&query.join("&"));
+    }
+    value
+}
+
+/// Reacts to a found duplicate according to the chat settings. Reply limits apply only to
+/// messages sent by the bot, duplicates are deleted regardless of them.
+pub async fn respond_to_duplicate(
+    msg: Message,
+    bot: AutoSend<Bot>,
+    context: &context::HandlerContext,
+    client: std::sync::Arc<db::ChatDatabase>,
+    reason: utils::SlowpokeReason,
+) -> anyhow::Result<()> {
+    let context::HandlerContext {
+        settings_db,
+        journal,
+        limiter,
+        ..
+    } = context;
+    let namespace = settings_db::Namespace::Chat(msg.chat.id.0);
+    let is_shadow = settings_db.get::<settings::ShadowMode>(namespace)?;
+    let action = settings_db.get::<settings::DuplicateAction>(namespace)?;
+
+    // Shadow mode doesn't consume the reply limits
+    let suppression = if is_shadow {
+        None
+    } else {
+        limiter
+            .allow_duplicate_reply(settings_db, msg.chat.id.0, utils::message_time(&msg))?
+            .err()
+    };
+
+    let user_id = msg.from().map(|user| user.id.0);
+    let detection_id = match client
+        .add_detection(
+            msg.id,
+            user_id,
+            &reason,
+            is_shadow,
+            suppression.as_ref().map(ToString::to_string),
+        )
+        .await
+    {
+        Ok(detection_id) => Some(detection_id),
+        Err(e) => {
+            log::warn!("Cannot record a detection: {:?}", e);
+            None
+        }
+    };
+
+    if is_shadow {
+        log::info!(
+            "Shadow mode: slowpoke for the message {} in the chat {} is suppressed: {}",
+            msg.id,
+            msg.chat.id,
+            reason
+        );
+        return Ok(());
+    }
+
+    if msg.chat.is_channel() {
+        return channel::send_alert(&msg, &bot, settings_db, journal, &reason).await;
+    }
+
+    let is_deleted = match action.mode {
+        settings::ActionMode::Reply => false,
+        settings::ActionMode::ReplyAndDelete => {
+            moderation::schedule_deletion(
+                bot.clone(),
+                context,
+                client.clone(),
+                msg.chat.id,
+                msg.id,
+                detection_id,
+                std::time::Duration::from_secs(action.delete_after_seconds),
+            );
+            false
+        }
+        settings::ActionMode::Delete => {
+            moderation::delete_duplicate(&bot, settings_db, msg.chat.id, msg.id).await?
+        }
+    };
+
+    if let Some(suppression) = suppression {
+        log::info!(
+            "Slowpoke for the message {} in the chat {} is suppressed by {}: {}",
+            msg.id,
+            msg.chat.id,
+            suppression,
+            reason
+        );
+        return Ok(());
+    }
+
+    if is_deleted {
+        // There is nothing to reply to anymore
+        journal.record(msg.chat.id.0, msg.id, &reason);
+        let author = msg
+            .from()
+            .map(|user| user.first_name.clone())
+            .unwrap_or_default();
+        bot.send_message(
+            msg.chat.id,
+            format!("Дубликат от {} удалён: такое уже было.", author),
+        )
+        .traced()
+        .await?;
+        return Ok(());
+    }
+
+    utils::send_slowpoke(
+        msg,
+        bot,
+        settings_db.clone(),
+        journal.clone(),
+        reason,
+        detection_id,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHAT_ID: i64 = -100;
+    const CHANNEL_ID: i64 = -1001;
+
+    fn message(text: &str) -> Message {
+        message_with(serde_json::json!({ "text": text }))
+    }
+
+    // A message of the user 1 in the test chat with the given fields
+    fn message_with(fields: serde_json::Value) -> Message {
+        let mut message = serde_json::json!({
+            "message_id": 1,
+            "date": 0,
+            "chat": {"id": CHAT_ID, "type": "supergroup", "title": "test"},
+            "from": {"id": 1, "is_bot": false, "first_name": "test"},
+        });
+        for (key, value) in fields.as_object().expect("Fields have to be an object") {
+            message[key] = value.clone();
+        }
+        serde_json::from_value(message).expect("Cannot build a test message")
+    }
+
+    fn forward(message_id: i32, fields: serde_json::Value) -> Message {
+        let mut forward = serde_json::json!({
+            "forward_from_chat": {"id": CHANNEL_ID, "type": "channel", "title": "channel"},
+            "forward_from_message_id": message_id,
+            "forward_date": 0,
+            "text": "post",
+        });
+        for (key, value) in fields.as_object().expect("Fields have to be an object") {
+            forward[key] = value.clone();
+        }
+        message_with(forward)
+    }
+
+    fn set_exemptions(
+        settings_db: &settings_db::SettingsDb,
+        exemptions: settings::ExemptionsValue,
+    ) {
+        settings_db
+            .set::<settings::Exemptions>(settings_db::Namespace::Chat(CHAT_ID), &exemptions)
+            .expect("Cannot set exemptions");
+    }
+
+    fn set_linked_channel(
+        settings_db: &settings_db::SettingsDb,
+        mode: settings::LinkedChannelMode,
+    ) {
+        settings_db
+            .set::<settings::LinkedChannel>(settings_db::Namespace::Chat(CHAT_ID), &mode)
+            .expect("Cannot set the linked channel mode");
+    }
+
+    fn forward_fingerprint(message_id: i32) -> Fingerprint {
+        Fingerprint {
+            detector: Detector::Forward,
+            value: format!("{}/{}", CHANNEL_ID, message_id),
+        }
+    }
+
+    fn normalized_link(link: &str) -> Option<String> {
+        normalize_link(link).map(|url| link_value(&url))
+    }
+
+    #[test]
+    fn links_without_scheme_are_accepted() {
+        assert_eq!(
+            normalized_link("example.com/page"),
+            Some("example.com/page".to_string())
+        );
+    }
+
+    #[test]
+    fn links_without_host_or_with_other_schemes_are_ignored() {
+        assert!(normalize_link("ftp://example.com/file").is_none());
+        assert!(normalize_link("mailto:user@example.com").is_none());
+        assert!(normalize_link("http://").is_none());
+    }
+
+    #[test]
+    fn hosts_with_ports_are_not_schemes() {
+        assert_eq!(
+            normalized_link("example.com:8080/page"),
+            Some("example.com/page".to_string())
+        );
+    }
+
+    #[test]
+    fn scheme_www_and_trailing_slash_are_ignored() {
+        let expected = Some("example.com/page".to_string());
+        assert_eq!(normalized_link("https://www.example.com/page/"), expected);
+        assert_eq!(normalized_link("http://example.com/page"), expected);
+        assert_eq!(normalized_link("HTTP://WWW.EXAMPLE.COM/page"), expected);
+    }
+
+    #[test]
+    fn tracking_parameters_are_removed() {
+        assert_eq!(
+            normalized_link("https://example.com/page?utm_source=tg&id=5&fbclid=abc&gclid=1"),
+            Some("example.com/page?id=5".to_string())
+        );
+        assert_eq!(
+            normalized_link("https://example.com/?utm_medium=social"),
+            Some("example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn other_parameters_keep_pages_apart() {
+        assert_ne!(
+            normalized_link("https://example.com/watch?v=1"),
+            normalized_link("https://example.com/watch?v=2")
+        );
+    }
+
+    #[test]
+    fn short_texts_are_ignored() {
+        assert!(text_value(&message("Всем привет!")).is_none());
+        // Punctuation and spacing don't count towards the length
+        let text = format!("{}!", "a ".repeat(MIN_TEXT_LENGTH / 2 - 1));
+        assert!(text_value(&message(&text)).is_none());
+    }
+
+    #[test]
+    fn texts_are_compared_regardless_of_case_punctuation_and_spacing() {
+        let text = "The quick brown fox jumps over the lazy dog, and then it runs away";
+        let value = text_value(&message(text));
+        assert!(value.is_some());
+        assert_eq!(
+            value,
+            text_value(&message(
+                "the QUICK brown fox -- jumps over the lazy dog!!!\n And then it runs away."
+            ))
+        );
+        assert_ne!(
+            value,
+            text_value(&message(
+                "The quick brown fox jumps over the lazy cat, and then it runs away"
+            ))
+        );
+    }
+
+    #[test]
+    fn exempt_users_are_ignored_but_their_edits_drop_fingerprints() {
+        let settings_db = settings_db::SettingsDb::temporary().unwrap();
+        set_exemptions(
+            &settings_db,
+            settings::ExemptionsValue {
+                users: vec![1],
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(
+            fingerprints_to_check(&forward(5, serde_json::json!({})), &settings_db),
+            None
+        );
+        let edit = message_with(serde_json::json!({"text": "edited", "edit_date": 1}));
+        assert_eq!(fingerprints_to_check(&edit, &settings_db), Some(Vec::new()));
+    }
+
+    #[test]
+    fn opted_out_users_are_ignored_but_their_edits_drop_fingerprints() {
+        let settings_db = settings_db::SettingsDb::temporary().unwrap();
+        privacy::set_opted_out(&settings_db, 1, true).unwrap();
+
+        assert_eq!(
+            fingerprints_to_check(&forward(5, serde_json::json!({})), &settings_db),
+            None
+        );
+        let edit = message_with(serde_json::json!({"text": "edited", "edit_date": 1}));
+        assert_eq!(fingerprints_to_check(&edit, &settings_db), Some(Vec::new()));
+    }
+
+    #[test]
+    fn forwards_are_identified_by_the_source_and_the_message() {
+        let settings_db = settings_db::SettingsDb::temporary().unwrap();
+
+        assert_eq!(
+            fingerprints_to_check(&forward(5, serde_json::json!({})), &settings_db),
+            Some(vec![forward_fingerprint(5)])
+        );
+    }
+
+    #[test]
+    fn forwards_from_exempt_sources_are_ignored() {
+        let settings_db = settings_db::SettingsDb::temporary().unwrap();
+        set_exemptions(
+            &settings_db,
+            settings::ExemptionsValue {
+                forward_sources: vec![CHANNEL_ID],
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(
+            fingerprints_to_check(&forward(5, serde_json::json!({})), &settings_db),
+            None
+        );
+    }
+
+    #[test]
+    fn links_to_exempt_domains_and_their_subdomains_are_skipped() {
+        let settings_db = settings_db::SettingsDb::temporary().unwrap();
+        set_exemptions(
+            &settings_db,
+            settings::ExemptionsValue {
+                domains: vec!["example.com".to_string()],
+                ..Default::default()
+            },
+        );
+
+        let text = "https://news.example.com/a https://other.org/b";
+        let msg = message_with(serde_json::json!({
+            "text": text,
+            "entities": [
+                {"type": "url", "offset": 0, "length": 26},
+                {"type": "url", "offset": 27, "length": 19},
+            ],
+        }));
+        assert_eq!(
+            fingerprints_to_check(&msg, &settings_db),
+            Some(vec![Fingerprint {
+                detector: Detector::Link,
+                value: "other.org/b".to_string(),
+            }])
+        );
+    }
+
+    #[test]
+    fn automatic_forwards_are_ignored_by_default_and_teach_the_linked_channel() {
+        let settings_db = settings_db::SettingsDb::temporary().unwrap();
+
+        let automatic_forward = forward(5, serde_json::json!({"is_automatic_forward": true}));
+        assert_eq!(
+            fingerprints_to_check(&automatic_forward, &settings_db),
+            None
+        );
+        assert_eq!(
+            settings_db
+                .get::<settings::LinkedChannelId>(settings_db::Namespace::Chat(CHAT_ID))
+                .unwrap(),
+            Some(CHANNEL_ID)
+        );
+        // Reposts of the channel posts by members are still checked
+        assert_eq!(
+            fingerprints_to_check(&forward(5, serde_json::json!({})), &settings_db),
+            Some(vec![forward_fingerprint(5)])
+        );
+    }
+
+    #[test]
+    fn automatic_forwards_are_recorded_in_the_record_mode() {
+        let settings_db = settings_db::SettingsDb::temporary().unwrap();
+        set_linked_channel(&settings_db, settings::LinkedChannelMode::Record);
+
+        let automatic_forward = forward(5, serde_json::json!({"is_automatic_forward": true}));
+        assert_eq!(
+            fingerprints_to_check(&automatic_forward, &settings_db),
+            Some(vec![forward_fingerprint(5)])
+        );
+    }
+
+    #[test]
+    fn forwards_from_the_linked_channel_are_ignored_in_the_exempt_mode() {
+        let settings_db = settings_db::SettingsDb::temporary().unwrap();
+        set_linked_channel(&settings_db, settings::LinkedChannelMode::Exempt);
+
+        let automatic_forward = forward(5, serde_json::json!({"is_automatic_forward": true}));
+        assert_eq!(
+            fingerprints_to_check(&automatic_forward, &settings_db),
+            None
+        );
+        assert_eq!(
+            fingerprints_to_check(&forward(5, serde_json::json!({})), &settings_db),
+            None
+        );
+    }
+}
@@ -0,0 +1,224 @@
+use crate::broadcast;
+use crate::db;
+use crate::error_report;
+use crate::logging::TracedRequest;
+use crate::parameters;
+use crate::privacy;
+use crate::reply_policy;
+use crate::settings;
+use crate::settings_db;
+use chrono::Timelike;
+use teloxide::prelude::*;
+
+// Number of entries in every list of the digest
+const LIST_LENGTH: usize = 3;
+
+// Opted out users are removed from the lists, so a few more are requested
+const QUERY_LIMIT: i64 = 10;
+
+/// Sends digests to the chats, where they are enabled and the period has passed. Failures of
+/// single chats are reported, so the other chats still get their digests.
+pub async fn send_due_digests(
+    bot: &AutoSend<Bot>,
+    pool_factory: &db::SqliteDatabasePoolFactory,
+    settings_db: &settings_db::SettingsDb,
+    parameters: &parameters::Parameters,
+    error_reporter: &std::sync::Arc<error_report::ErrorReporter>,
+) -> anyhow::Result<()> {
+    for chat_id in broadcast::target_chats(pool_factory, settings_db, parameters)? {
+        if let Err(e) = send_digest_if_due(chat_id, bot, pool_factory, settings_db).await {
+            error_reporter.report(error_report::with_chat(
+                e.context("Cannot send a digest"),
+                chat_id,
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+async fn send_digest_if_due(
+    chat_id: i64,
+    bot: &AutoSend<Bot>,
+    pool_factory: &db::SqliteDatabasePoolFactory,
+    settings_db: &settings_db::SettingsDb,
+) -> anyhow::Result<()> {
+    let namespace = settings_db::Namespace::Chat(chat_id);
+    let digest = settings_db.get::<settings::Digest>(namespace)?;
+    if !digest.is_enabled {
+        return Ok(());
+    }
+
+    // Days are counted in the chat timezone, so the digest comes at the same local time
+    let timezone = reply_policy::chat_timezone(settings_db, chat_id)?;
+    let now = chrono::Utc::now().with_timezone(&timezone);
+    if now.hour() < digest.hour {
+        return Ok(());
+    }
+    if let Some(sent_at) = settings_db.get::<settings::DigestSentAt>(namespace)? {
+        let sent_on = chrono::DateTime::from_timestamp(sent_at, 0)
+            .unwrap_or_default()
+            .with_timezone(&timezone)
+            .date_naive();
+        if (now.date_naive() - sent_on).num_days() < i64::from(digest.period_days) {
+            return Ok(());
+        }
+    }
+
+    // Reposts in channels go to their alert chats, so only groups get digests
+    if bot.get_chat(ChatId(chat_id)).traced().await?.is_channel() {
+        log::debug!(
+            "Chat with id={} is a channel, the digest is skipped",
+            chat_id
+        );
+        return Ok(());
+    }
+
+    match digest_text(chat_id, digest.period_days, bot, pool_factory, settings_db).await? {
+        Some(text) => {
+            bot.send_message(ChatId(chat_id), text)
+                .disable_web_page_preview(true)
+                .traced()
+                .await?;
+            log::info!("Sent a digest to the chat {}", chat_id);
+        }
+        None => log::debug!("Chat with id={} had no reposts for the digest", chat_id),
+    }
+
+    // Marked only after sending, so a failed digest is sent again on the next check
+    settings_db.set::<settings::DigestSentAt>(namespace, &Some(now.timestamp()))?;
+
+    Ok(())
+}
+
+// Returns nothing, if there were no reposts during the period
+async fn digest_text(
+    chat_id: i64,
+    days: u32,
+    bot: &AutoSend<Bot>,
+    pool_factory: &db::SqliteDatabasePoolFactory,
+    settings_db: &settings_db::SettingsDb,
+) -> anyhow::Result<Option<String>> {
+    let client = pool_factory.create(chat_id).await?;
+
+    let reposted = client.most_reposted(days, LIST_LENGTH as i64).await?;
+    if reposted.is_empty() {
+        return Ok(None);
+    }
+    let reposters = visible_users(client.top_reposters(days, QUERY_LIMIT).await?, settings_db);
+    let original_posters = visible_users(
+        client.top_original_posters(days, QUERY_LIMIT).await?,
+        settings_db,
+    );
+
+    let is_weekly = days == 7;
+    let mut text = if is_weekly {
+        "Итоги недели".to_string()
+    } else {
+        format!("Итоги за {} дн.", days)
+    };
+
+    text.push_str("\n\nЧаще всего повторяли:");
+    for (index, (detector, value, message_id, count)) in reposted.into_iter().enumerate() {
+        text.push_str(&format!(
+            "\n{}. {} - повторов: {}",
+            index + 1,
+            content_description(chat_id, &detector, value, message_id),
+            count
+        ));
+    }
+
+    // The worst offender gets the title
+    let mut slowpoke = None;
+    if !reposters.is_empty() {
+        text.push_str("\n\nГлавные слоупоки:");
+        for (index, &(user_id, count)) in reposters.iter().enumerate() {
+            let name = user_name(bot, chat_id, user_id).await;
+            text.push_str(&format!("\n{}. {} - повторов: {}", index + 1, name, count));
+            slowpoke.get_or_insert(name);
+        }
+    }
+
+    if !original_posters.is_empty() {
+        text.push_str("\n\nПервыми успели:");
+        for (index, &(user_id, count)) in original_posters.iter().enumerate() {
+            text.push_str(&format!(
+                "\n{}. {} - постов, которые повторили другие: {}",
+                index + 1,
+                user_name(bot, chat_id, user_id).await,
+                count
+            ));
+        }
+    }
+
+    if let Some(slowpoke) = slowpoke {
+        text.push_str(&format!(
+            "\n\n{}: {}!",
+            if is_weekly {
+                "Слоупок недели"
+            } else {
+                "Слоупок периода"
+            },
+            slowpoke
+        ));
+    }
+
+    Ok(Some(text))
+}
+
+// Users, who opted out, are never named
+fn visible_users(users: Vec<(i64, i64)>, settings_db: &settings_db::SettingsDb) -> Vec<(i64, i64)> {
+    users
+        .into_iter()
+        .filter(|&(user_id, _)| !privacy::is_opted_out(settings_db, user_id as u64))
+        .take(LIST_LENGTH)
+        .collect()
+}
+
+fn content_description(
+    chat_id: i64,
+    detector: &str,
+    value: Option<String>,
+    message_id: Option<i32>,
+) -> String {
+    if let ("link", Some(value)) = (detector, value) {
+        return value;
+    }
+
+    let kind = match detector {
+        "forward" => "пересылка",
+        "link" => "ссылка",
+        "text" => "текст",
+        "media" => "медиа",
+        _ => "сообщение",
+    };
+    // Messages moved from a group, which became a supergroup, have no ids. Only supergroups and
+    // channels have links to messages.
+    match (message_id, chat_id.to_string().strip_prefix("-100")) {
+        (None, _) => kind.to_string(),
+        (Some(message_id), Some(internal_id)) => {
+            format!("{} https://t.me/c/{}/{}", kind, internal_id, message_id)
+        }
+        (Some(message_id), None) => format!("{} (сообщение {})", kind, message_id),
+    }
+}
+
+// Plain names, so the digest doesn't notify everyone it mentions
+async fn user_name(bot: &AutoSend<Bot>, chat_id: i64, user_id: i64) -> String {
+    match bot
+        .get_chat_member(ChatId(chat_id), UserId(user_id as u64))
+        .traced()
+        .await
+    {
+        Ok(member) => member.user.full_name(),
+        Err(e) => {
+            log::debug!(
+                "Cannot get the user {} of the chat {}: {:?}",
+                user_id,
+                chat_id,
+                e
+            );
+            format!("пользователь {}", user_id)
+        }
+    }
+}
@@ -0,0 +1,239 @@
+use crate::background;
+use crate::logging::TracedRequest;
+use teloxide::prelude::*;
+
+// Failures kept for the /errors command
+const RECENT_FAILURES_LIMIT: usize = 20;
+
+// Error texts are shortened, so a digest fits into a message
+const MAX_ERROR_TEXT_LENGTH: usize = 300;
+
+/// The chat, which handling failed
+#[derive(Debug)]
+struct ChatContext(i64);
+
+impl std::fmt::Display for ChatContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "chat {}", self.0)
+    }
+}
+
+/// Marks the error as related to the chat. Other contexts have to be added before.
+pub fn with_chat(error: anyhow::Error, chat_id: i64) -> anyhow::Error {
+    error.context(ChatContext(chat_id))
+}
+
+/// Adds the chat of the update to errors of the rest of the handler tree
+pub fn attach_chat() -> teloxide::dispatching::UpdateHandler<anyhow::Error> {
+    crate::utils::around_updates(|deps, cont| async move {
+        let update: std::sync::Arc<Update> = dptree::di::DependencySupplier::get(&deps);
+        let chat_id = update.chat().map(|chat| chat.id.0);
+
+        match (cont(deps).await, chat_id) {
+            (std::ops::ControlFlow::Break(Err(e)), Some(chat_id)) => {
+                std::ops::ControlFlow::Break(Err(with_chat(e, chat_id)))
+            }
+            (result, _) => result,
+        }
+    })
+}
+
+struct Failure {
+    time: chrono::DateTime<chrono::Utc>,
+    kind: &'static str,
+    chat_id: Option<i64>,
+    text: String,
+}
+
+#[derive(Default)]
+struct KindSummary {
+    count: u64,
+    last_example: String,
+    chats: std::collections::BTreeSet<i64>,
+}
+
+#[derive(Default)]
+struct State {
+    recent: std::collections::VecDeque<Failure>,
+    // Errors since the previous digest
+    pending: std::collections::BTreeMap<&'static str, KindSummary>,
+    last_digest_at: Option<std::time::Instant>,
+    is_digest_scheduled: bool,
+}
+
+/// Handles errors of the dispatcher. The owner gets digests with errors grouped by kind, not
+/// more often than once per `digest_periodicity`.
+pub struct ErrorReporter {
+    bot: AutoSend<Bot>,
+    owner_id: u64,
+    digest_periodicity: std::time::Duration,
+    tasks: std::sync::Arc<background::BackgroundTasks>,
+    state: std::sync::Mutex<State>,
+}
+
+impl ErrorReporter {
+    pub fn new(
+        bot: AutoSend<Bot>,
+        owner_id: u64,
+        digest_periodicity: std::time::Duration,
+        tasks: std::sync::Arc<background::BackgroundTasks>,
+    ) -> Self {
+        Self {
+            bot,
+            owner_id,
+            digest_periodicity,
+            tasks,
+            state: Default::default(),
+        }
+    }
+
+    /// Text for the /errors command, the newest failures go first
+    pub fn recent_failures_text(&self) -> String {
+        let state = self.state.lock().expect("Error reporter mutex is poisoned");
+        if state.recent.is_empty() {
+            return "Ошибок не было.".to_string();
+        }
+
+        let mut text = "Последние ошибки:".to_string();
+        for failure in state.recent.iter().rev() {
+            text.push_str(&format!(
+                "\n{} [{}]{}: {}",
+                failure.time.format("%Y-%m-%d %H:%M:%S"),
+                failure.kind,
+                failure
+                    .chat_id
+                    .map(|chat_id| format!(" чат {}", chat_id))
+                    .unwrap_or_default(),
+                failure.text
+            ));
+        }
+        text
+    }
+
+    /// Logs the error and sends it to the owner with the next digest. Used for failures of
+    /// background tasks, the dispatcher reports errors of the handlers itself.
+    pub fn report(self: &std::sync::Arc<Self>, error: anyhow::Error) {
+        log::error!("An error has occurred: {:?}", error);
+
+        // Callers don't wait for the digest, so it is sent separately
+        if let Some(delay) = self.record(&error) {
+            let reporter = self.clone();
+            self.tasks.spawn("error_digest", delay, async move {
+                if let Err(e) = reporter.send_digest().await {
+                    log::warn!("Cannot send an error digest to the owner: {:?}", e);
+                }
+            });
+        }
+    }
+
+    // Returns the delay of the next digest, if it has to be scheduled
+    fn record(&self, error: &anyhow::Error) -> Option<std::time::Duration> {
+        let chat_id = error.downcast_ref::<ChatContext>().map(|context| context.0);
+        // The chat is shown separately. Some errors repeat their cause in the message.
+        let mut causes: Vec<String> = Vec::new();
+        for cause in error.chain().skip(usize::from(chat_id.is_some())) {
+            let cause = cause.to_string();
+            if !causes.last().is_some_and(|last| last.ends_with(&cause)) {
+                causes.push(cause);
+            }
+        }
+        let mut text = causes.join(": ");
+        if let Some((index, _)) = text.char_indices().nth(MAX_ERROR_TEXT_LENGTH) {
+            text.truncate(index);
+            text.push('…');
+        }
+        let kind = error_kind(error);
+
+        let mut state = self.state.lock().expect("Error reporter mutex is poisoned");
+
+        let summary = state.pending.entry(kind).or_default();
+        summary.count += 1;
+        summary.last_example = text.clone();
+        summary.chats.extend(chat_id);
+
+        if state.recent.len() == RECENT_FAILURES_LIMIT {
+            state.recent.pop_front();
+        }
+        state.recent.push_back(Failure {
+            time: chrono::Utc::now(),
+            kind,
+            chat_id,
+            text,
+        });
+
+        if state.is_digest_scheduled {
+            return None;
+        }
+        state.is_digest_scheduled = true;
+        Some(
+            state
+                .last_digest_at
+                .map(|last_digest_at| {
+                    (last_digest_at + self.digest_periodicity)
+                        .saturating_duration_since(std::time::Instant::now())
+                })
+                .unwrap_or_default(),
+        )
+    }
+
+    async fn send_digest(&self) -> anyhow::Result<()> {
+        let pending = {
+            let mut state = self.state.lock().expect("Error reporter mutex is poisoned");
+            state.last_digest_at = Some(std::time::Instant::now());
+            state.is_digest_scheduled = false;
+            std::mem::take(&mut state.pending)
+        };
+
+        let mut text = "В работе бота были ошибки:".to_string();
+        for (kind, summary) in pending {
+            let chats: Vec<String> = summary.chats.iter().map(ToString::to_string).collect();
+            text.push_str(&format!("\n\n{}: {} раз", kind, summary.count));
+            if !chats.is_empty() {
+                text.push_str(&format!(", чаты {}", chats.join(", ")));
+            }
+            text.push_str(&format!("\nПоследняя: {}", summary.last_example));
+        }
+        text.push_str("\n\nПодробнее: /errors");
+
+        self.bot
+            .send_message(UserId(self.owner_id), text)
+            .traced()
+            .await?;
+        Ok(())
+    }
+}
+
+impl teloxide::error_handlers::ErrorHandler<anyhow::Error> for ErrorReporter {
+    fn handle_error(
+        self: std::sync::Arc<Self>,
+        error: anyhow::Error,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send>> {
+        self.report(error);
+        Box::pin(async {})
+    }
+}
+
+fn error_kind(error: &anyhow::Error) -> &'static str {
+    for cause in error.chain() {
+        if let Some(error) = cause.downcast_ref::<teloxide::RequestError>() {
+            return match error {
+                teloxide::RequestError::Api(_) => "Telegram API",
+                teloxide::RequestError::Network(_) | teloxide::RequestError::Io(_) => {
+                    "сеть Telegram"
+                }
+                _ => "Telegram",
+            };
+        }
+        if cause.is::<sqlx::Error>() {
+            return "база данных чата";
+        }
+        if cause.is::<sled::Error>() {
+            return "настройки";
+        }
+        if cause.is::<std::io::Error>() {
+            return "файловая система";
+        }
+    }
+
+    "прочее"
+}
@@ -0,0 +1,102 @@
+use crate::db;
+use crate::logging::TracedRequest;
+use crate::parameters;
+use crate::utils;
+use teloxide::prelude::*;
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup};
+
+// Followed by the detection id, which is empty for slowpokes requested by the command
+const NOT_DUPLICATE_PREFIX: &str = "not_duplicate:";
+
+pub fn keyboard(detection_id: Option<i64>) -> InlineKeyboardMarkup {
+    let data = format!(
+        "{}{}",
+        NOT_DUPLICATE_PREFIX,
+        detection_id.map(|id| id.to_string()).unwrap_or_default()
+    );
+    InlineKeyboardMarkup::new(vec![vec![InlineKeyboardButton::callback(
+        "Не дубликат",
+        data,
+    )]])
+}
+
+pub fn is_not_duplicate_callback(query: &CallbackQuery) -> bool {
+    query
+        .data
+        .as_deref()
+        .is_some_and(|data| data.starts_with(NOT_DUPLICATE_PREFIX))
+}
+
+/// Deletes the slowpoke reply and marks the detection as a false positive. Only the author of
+/// the message, which got the reply, and chat administrators can do it.
+pub async fn not_duplicate_callback_handler(
+    query: CallbackQuery,
+    bot: AutoSend<Bot>,
+    pool_factory: std::sync::Arc<db::SqliteDatabasePoolFactory>,
+    parameters: std::sync::Arc<parameters::Parameters>,
+) -> anyhow::Result<()> {
+    let reply = match query.message {
+        Some(reply) => reply,
+        None => {
+            bot.answer_callback_query(query.id).traced().await?;
+            return Ok(());
+        }
+    };
+
+    let is_author = reply
+        .reply_to_message()
+        .and_then(|message| message.from())
+        .is_some_and(|author| author.id == query.from.id);
+    if !is_author
+        && !utils::is_user_an_admin(&reply.chat, query.from.id, &bot, parameters.owner_id).await
+    {
+        static PERMISSION_DENIED: &str =
+            "Отметить ответ может только автор сообщения или администратор чата.";
+        bot.answer_callback_query(query.id)
+            .text(PERMISSION_DENIED)
+            .traced()
+            .await?;
+        return Ok(());
+    }
+
+    let detection_id = query
+        .data
+        .as_deref()
+        .and_then(|data| data.strip_prefix(NOT_DUPLICATE_PREFIX))
+        .filter(|detection_id| !detection_id.is_empty())
+        .map(str::parse::<i64>)
+        .transpose()?;
+
+    if let Some(detection_id) = detection_id {
+        let client = pool_factory.create(reply.chat.id.0).await?;
+        if client
+            .add_false_positive(detection_id, query.from.id.0)
+            .await?
+        {
+            log::info!(
+                "The detection {} in the chat {} was marked as a false positive by the user {}",
+                detection_id,
+                reply.chat.id,
+                query.from.id
+            );
+        } else {
+            log::warn!(
+                "Cannot find the detection {} in the chat {}",
+                detection_id,
+                reply.chat.id
+            );
+        }
+    }
+
+    if let Err(e) = bot.delete_message(reply.chat.id, reply.id).traced().await {
+        log::warn!("Cannot delete the slowpoke reply: {:?}", e);
+    }
+
+    static MARKED_AS_NOT_DUPLICATE: &str = "Спасибо, ответ удалён.";
+    bot.answer_callback_query(query.id)
+        .text(MARKED_AS_NOT_DUPLICATE)
+        .traced()
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,67 @@
+use crate::logging::TracedRequest;
+use crate::settings;
+use crate::settings_db;
+use teloxide::prelude::*;
+use teloxide::types::{
+    InlineQueryResult, InlineQueryResultArticle, InlineQueryResultCachedPhoto, InputMessageContent,
+    InputMessageContentText,
+};
+
+// Telegram limits media captions to 1024 characters
+const MAX_CAPTION_LENGTH: usize = 1024;
+// Telegram rejects answers with more results
+const MAX_RESULTS: usize = 50;
+// Images rarely change, but a new one should show up without a long wait
+const CACHE_TIME_IN_SECONDS: u32 = 60;
+
+/// Offers the reply image and the alternate images, so a slowpoke can be sent to any chat.
+/// The query text becomes the caption.
+pub async fn inline_query_handler(
+    query: InlineQuery,
+    bot: AutoSend<Bot>,
+    settings_db: std::sync::Arc<settings_db::SettingsDb>,
+) -> anyhow::Result<()> {
+    let mut images: Vec<settings::ImageFileId> = settings_db
+        .get::<settings::ReplyImage>(settings_db::Namespace::Global)?
+        .into_iter()
+        .collect();
+    images.extend(settings_db.get::<settings::AlternateImages>(settings_db::Namespace::Global)?);
+
+    let caption: String = query
+        .query
+        .trim()
+        .chars()
+        .take(MAX_CAPTION_LENGTH)
+        .collect();
+
+    let results: Vec<InlineQueryResult> = if images.is_empty() {
+        static MISSED_SLOWPOKE_IN_DATABASE: &str = "Слоупоки закончились :(.";
+        vec![InlineQueryResultArticle::new(
+            "missed",
+            MISSED_SLOWPOKE_IN_DATABASE,
+            InputMessageContent::Text(InputMessageContentText::new(MISSED_SLOWPOKE_IN_DATABASE)),
+        )
+        .into()]
+    } else {
+        images
+            .into_iter()
+            .take(MAX_RESULTS)
+            .enumerate()
+            .map(|(index, image)| {
+                let mut result =
+                    InlineQueryResultCachedPhoto::new(format!("image{}", index), image.file_id);
+                if !caption.is_empty() {
+                    result = result.caption(caption.clone());
+                }
+                result.into()
+            })
+            .collect()
+    };
+
+    bot.answer_inline_query(query.id, results)
+        .cache_time(CACHE_TIME_IN_SECONDS)
+        .traced()
+        .await?;
+
+    Ok(())
+}
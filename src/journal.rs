@@ -0,0 +1,54 @@
+/// Keeps the reasons of slowpoke replies. Only the replay mode enables it,
+/// in the normal mode the reasons are just logged.
+pub struct ReplyJournal {
+    entries: Option<std::sync::Mutex<Vec<JournalEntry>>>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct JournalEntry {
+    pub chat_id: i64,
+    pub message_id: i32,
+    pub reason: String,
+}
+
+impl ReplyJournal {
+    pub fn disabled() -> Self {
+        Self { entries: None }
+    }
+
+    pub fn recording() -> Self {
+        Self {
+            entries: Some(Default::default()),
+        }
+    }
+
+    pub fn record(&self, chat_id: i64, message_id: i32, reason: &crate::utils::SlowpokeReason) {
+        log::info!(
+            "Slowpoke for the message {} in the chat {}: {}",
+            message_id,
+            chat_id,
+            reason
+        );
+
+        if let Some(entries) = &self.entries {
+            entries
+                .lock()
+                .expect("Reply journal mutex is poisoned")
+                .push(JournalEntry {
+                    chat_id,
+                    message_id,
+                    reason: reason.to_string(),
+                });
+        }
+    }
+
+    /// Returns all entries recorded since the previous call.
+    pub fn take(&self) -> Vec<JournalEntry> {
+        match &self.entries {
+            Some(entries) => {
+                std::mem::take(&mut *entries.lock().expect("Reply journal mutex is poisoned"))
+            }
+            None => Vec::new(),
+        }
+    }
+}
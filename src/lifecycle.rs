@@ -0,0 +1,90 @@
+use crate::allowlist;
+use crate::context;
+use crate::db;
+use crate::parameters;
+use crate::settings;
+use crate::settings_db;
+use teloxide::prelude::*;
+use teloxide::types::ChatMemberUpdated;
+
+/// Marks chats, which the bot was removed from, and unmarks them, when the bot comes back
+/// before their data is gone.
+pub async fn my_chat_member_handler(
+    update: ChatMemberUpdated,
+    bot: AutoSend<Bot>,
+    context: std::sync::Arc<context::HandlerContext>,
+) -> anyhow::Result<()> {
+    let settings_db = &context.settings_db;
+    let namespace = settings_db::Namespace::Chat(update.chat.id.0);
+    let was_present = update.old_chat_member.is_present();
+    let is_present = update.new_chat_member.is_present();
+
+    if is_present && !was_present {
+        log::info!("The bot was added to the chat {}", update.chat.id);
+        if settings_db.get::<settings::RemovedAt>(namespace)?.is_some() {
+            settings_db.set::<settings::RemovedAt>(namespace, &None)?;
+            log::info!("The chat {} is active again", update.chat.id);
+        }
+        allowlist::is_chat_allowed(&update.chat, &bot, &context).await;
+    } else if was_present && !is_present {
+        log::info!("The bot was removed from the chat {}", update.chat.id);
+        settings_db.set::<settings::RemovedAt>(namespace, &Some(chrono::Utc::now().timestamp()))?;
+    }
+
+    Ok(())
+}
+
+/// Archives or deletes data of the removed chat, when its grace period is over.
+/// Returns whether the data was removed.
+pub async fn expire_removed_chat(
+    chat_id: i64,
+    removed_at: i64,
+    pool_factory: &db::SqliteDatabasePoolFactory,
+    settings_db: &settings_db::SettingsDb,
+    parameters: &parameters::Parameters,
+) -> anyhow::Result<bool> {
+    let removed_for = chrono::Utc::now().timestamp() - removed_at;
+    if removed_for < parameters.removed_chat_grace_period.as_secs() as i64 {
+        return Ok(false);
+    }
+
+    if parameters.is_removed_chat_archive_enabled {
+        archive_chat(chat_id, pool_factory, settings_db, &parameters.archive_path).await?;
+    }
+
+    pool_factory.purge(chat_id).await?;
+    settings_db.remove_chat(chat_id)?;
+    log::info!(
+        "Data of the chat {}, which the bot was removed from, is {}",
+        chat_id,
+        if parameters.is_removed_chat_archive_enabled {
+            "archived"
+        } else {
+            "deleted"
+        }
+    );
+
+    Ok(true)
+}
+
+async fn archive_chat(
+    chat_id: i64,
+    pool_factory: &db::SqliteDatabasePoolFactory,
+    settings_db: &settings_db::SettingsDb,
+    archive_path: &std::path::Path,
+) -> anyhow::Result<()> {
+    std::fs::create_dir_all(archive_path)?;
+    let name = format!("{}-{}", chat_id, chrono::Utc::now().format("%Y%m%d-%H%M%S"));
+
+    let client = pool_factory.create(chat_id).await?;
+    client
+        .snapshot_into(&archive_path.join(format!("{}.db", name)))
+        .await?;
+
+    std::fs::write(
+        archive_path.join(format!("{}.settings.json", name)),
+        serde_json::to_vec_pretty(&settings_db.export_chat(chat_id)?)?,
+    )?;
+
+    Ok(())
+}
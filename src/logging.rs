@@ -1,6 +1,82 @@
-pub fn init_logger() {
+use dptree::di::DependencySupplier;
+use opentelemetry_otlp::WithExportConfig;
+use teloxide::prelude::*;
+use teloxide::requests::Payload;
+use tracing::Instrument;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+/// Logs go to stdout as JSON. With an OTLP endpoint, spans are also exported to a collector.
+pub fn init_logger(otlp_endpoint: Option<&str>) {
+    let otlp_layer = otlp_endpoint.map(|endpoint| {
+        let tracer = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(endpoint),
+            )
+            .with_trace_config(opentelemetry::sdk::trace::config().with_resource(
+                opentelemetry::sdk::Resource::new(vec![opentelemetry::KeyValue::new(
+                    "service.name",
+                    env!("CARGO_PKG_NAME"),
+                )]),
+            ))
+            .install_batch(opentelemetry::runtime::Tokio)
+            .expect("Cannot set up the OTLP exporter");
+
+        // Spans are exported regardless of RUST_LOG, which is only for the local logs
+        tracing_opentelemetry::layer()
+            .with_tracer(tracer)
+            .with_filter(tracing_subscriber::filter::LevelFilter::INFO)
+    });
+
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .json()
+                .with_filter(tracing_subscriber::EnvFilter::from_default_env()),
+        )
+        .with(otlp_layer)
+        .init();
+}
+
+/// Sends the spans, which are not exported yet
+pub fn shutdown_logger() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// Command line tools print their results to stdout, so logs go to stderr
+pub fn init_cli_logger() {
     tracing_subscriber::fmt()
         .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-        .json()
+        .with_writer(std::io::stderr)
         .init();
 }
+
+/// Runs the rest of the handler tree in a span of the update. The detector, which found
+/// a duplicate, is recorded into the span later.
+pub fn trace_updates() -> teloxide::dispatching::UpdateHandler<anyhow::Error> {
+    crate::utils::around_updates(|deps, cont| async move {
+        let update: std::sync::Arc<Update> = deps.get();
+        let span = tracing::info_span!(
+            "update",
+            update_id = update.id,
+            chat_id = update.chat().map(|chat| chat.id.0),
+            user_id = update.user().map(|user| user.id.0),
+            detector = tracing::field::Empty,
+        );
+        cont(deps).instrument(span).await
+    })
+}
+
+pub trait TracedRequest: Request + Sized {
+    /// Sends the request in a span named after the Telegram method
+    fn traced(self) -> tracing::instrument::Instrumented<Self::Send> {
+        let span = tracing::info_span!("telegram", method = <Self::Payload as Payload>::NAME);
+        self.send().instrument(span)
+    }
+}
+
+impl<R: Request> TracedRequest for R {}
@@ -1,11 +1,35 @@
+mod allowlist;
+mod background;
+mod backup;
+mod broadcast;
+mod channel;
+mod chat_migration;
+mod cli;
 mod commands;
+mod context;
 mod db;
+mod detection;
+mod digest;
+mod error_report;
+mod feedback;
+mod inline;
+mod journal;
+mod lifecycle;
 mod logging;
+mod maintenance;
+mod moderation;
 mod parameters;
+mod privacy;
+mod replay;
+mod reply_policy;
+mod settings;
 mod settings_db;
+mod status;
 mod utils;
 mod webhook;
 
+use clap::Parser;
+use logging::TracedRequest;
 use teloxide::prelude::*;
 
 #[macro_use]
@@ -13,74 +37,85 @@ extern crate anyhow;
 
 #[tokio::main]
 async fn main() {
-    run().await;
+    let cli = cli::Cli::parse();
+
+    match cli.command {
+        None => run().await,
+        Some(command) => {
+            logging::init_cli_logger();
+            if let Err(e) = cli::execute(command).await {
+                eprintln!("Error: {:#}", e);
+                std::process::exit(1);
+            }
+        }
+    }
 }
 
 async fn run() {
-    logging::init_logger();
-    log::info!("Starting slowpoke bot");
-
     let parameters = std::sync::Arc::new(parameters::Parameters::new());
+    logging::init_logger(parameters.otlp_endpoint.as_deref());
+    log::info!("Starting slowpoke bot {}", parameters.bot_name);
 
-    let settings_db = std::sync::Arc::new(tokio::sync::Mutex::new(
+    let settings_db = std::sync::Arc::new(
         settings_db::SettingsDb::new(parameters.settings_database_path.as_path())
             .expect("Cannot open settings database"),
+    );
+
+    let pool_factory = std::sync::Arc::new(db::SqliteDatabasePoolFactory::new(
+        parameters.chat_database_root_path.clone(),
+        parameters.max_database_connections_count,
     ));
 
-    let pool_factory =
-        std::sync::Arc::new(tokio::sync::Mutex::new(db::SqliteDatabasePoolFactory::new(
-            parameters.chat_database_root_path.clone(),
-            parameters.max_database_connections_count,
-        )));
+    if parameters.is_allowlist_mode_enabled {
+        let chat_ids = pool_factory
+            .list_existing_chats()
+            .expect("Cannot list existing chats");
+        allowlist::approve_existing_chats(chat_ids, &settings_db)
+            .expect("Cannot approve existing chats");
+    }
 
     let bot = Bot::from_env().auto_send();
+    let runtime = std::sync::Arc::new(status::RuntimeStatus::new());
+    let tasks = std::sync::Arc::new(background::BackgroundTasks::spawning());
+    let error_reporter = std::sync::Arc::new(error_report::ErrorReporter::new(
+        bot.clone(),
+        parameters.owner_id,
+        parameters.error_report_periodicity,
+        tasks.clone(),
+    ));
 
-    let message_clean_periodicity = parameters.message_clean_periodicity;
-    let clean_databases_factory = pool_factory.clone();
-    let _ = tokio::spawn(async move {
-        let mut interval = tokio::time::interval(message_clean_periodicity);
-        loop {
-            interval.tick().await;
-            clean_databases(clean_databases_factory.clone()).await;
-        }
-    });
+    maintenance::spawn(
+        bot.clone(),
+        pool_factory.clone(),
+        settings_db.clone(),
+        parameters.clone(),
+        runtime.clone(),
+        error_reporter.clone(),
+    );
 
-    let handler = Update::filter_message()
-        .branch(
-            dptree::entry()
-                .filter_command::<commands::Command>()
-                .endpoint(commands::command_handler),
-        )
-        .branch(
-            dptree::filter(|msg: Message| msg.forward_from_message_id().is_some()).endpoint(
-                |msg: Message,
-                 bot: AutoSend<Bot>,
-                 pool_factory: std::sync::Arc<
-                    tokio::sync::Mutex<db::SqliteDatabasePoolFactory>,
-                >,
-                 settings_db: std::sync::Arc<tokio::sync::Mutex<settings_db::SettingsDb>>| async move {
-                    process_forward_message(pool_factory.clone(), settings_db.clone(), msg, bot)
-                        .await?;
-                    anyhow::Result::Ok(())
-                },
-            ),
-        );
+    let handler = build_handler();
 
     if !parameters.is_webhook_mode_enabled {
         log::info!("Webhook deleted");
-        bot.delete_webhook().await.expect("Cannot delete a webhook");
+        bot.delete_webhook()
+            .traced()
+            .await
+            .expect("Cannot delete a webhook");
     }
 
     let mut bot_dispatcher = Dispatcher::builder(bot.clone(), handler)
         .dependencies(dptree::deps![
             pool_factory,
             settings_db,
-            parameters.owner_id
+            parameters.clone(),
+            std::sync::Arc::new(journal::ReplyJournal::disabled()),
+            std::sync::Arc::new(reply_policy::ReplyLimiter::default()),
+            error_reporter.clone(),
+            runtime,
+            tasks
         ])
         .default_handler(|_| async move {})
-        .error_handler(LoggingErrorHandler::with_custom_text(
-            "An error has occurred in the dispatcher",
-        ))
+        .error_handler(error_reporter)
         .enable_ctrlc_handler()
         .build();
 
@@ -97,51 +132,162 @@ async fn run() {
         log::info!("Long polling mode activated");
         bot_dispatcher.dispatch().await;
     }
+
+    logging::shutdown_logger();
 }
 
-async fn clean_databases(
-    pool_factory: std::sync::Arc<tokio::sync::Mutex<db::SqliteDatabasePoolFactory>>,
-) {
-    let chat_ids = pool_factory.lock().await.list_existing_chats();
-
-    for chat_id in chat_ids {
-        match pool_factory.lock().await.create(chat_id).await {
-            Ok(chat) => match chat.clean_old_messages().await {
-                Ok(_) => log::debug!("Chat with id={} cleaned successfully", chat_id),
-                Err(e) => log::warn!("Error during chat with id={} cleaning: {}", chat_id, e),
-            },
-            Err(e) => log::warn!("Cannot open a chat database: {}", e),
-        }
-    }
+// The replay mode runs recorded updates through the same handler
+fn build_handler() -> teloxide::dispatching::UpdateHandler<anyhow::Error> {
+    // The data is moved even without an approval, since the old chat was approved
+    let message_handler = Update::filter_message()
+        .branch(
+            dptree::filter(|msg: Message| chat_migration::is_migration(&msg))
+                .endpoint(chat_migration::migration_handler),
+        )
+        .chain(allowed_chat_filter())
+        .branch(
+            dptree::entry()
+                .filter_command::<commands::Command>()
+                .endpoint(commands::command_handler),
+        )
+        .branch(
+            dptree::entry()
+                .filter_command::<commands::OwnerCommand>()
+                .endpoint(commands::owner_command_handler),
+        )
+        .branch(detection_handler());
+
+    // Edits are checked like new messages, but commands are not executed again
+    let edited_message_handler = Update::filter_edited_message()
+        .chain(allowed_chat_filter())
+        .branch(detection_handler());
+
+    // Channels have no commands, only the detectors
+    let channel_post_handler = dptree::entry()
+        .branch(
+            Update::filter_channel_post()
+                .chain(allowed_chat_filter())
+                .chain(detection_handler()),
+        )
+        .branch(
+            Update::filter_edited_channel_post()
+                .chain(allowed_chat_filter())
+                .chain(detection_handler()),
+        );
+
+    let callback_query_handler = Update::filter_callback_query()
+        .branch(
+            dptree::filter(|query: CallbackQuery| allowlist::is_approval_callback(&query))
+                .endpoint(allowlist::approval_callback_handler),
+        )
+        .branch(
+            dptree::filter(|query: CallbackQuery| feedback::is_not_duplicate_callback(&query))
+                .endpoint(feedback::not_duplicate_callback_handler),
+        );
+
+    logging::trace_updates()
+        .chain(error_report::attach_chat())
+        .chain(context::inject())
+        .branch(message_handler)
+        .branch(edited_message_handler)
+        .branch(channel_post_handler)
+        .branch(Update::filter_inline_query().endpoint(inline::inline_query_handler))
+        .branch(Update::filter_my_chat_member().endpoint(lifecycle::my_chat_member_handler))
+        .branch(callback_query_handler)
 }
 
-async fn process_forward_message(
-    pool_factory: std::sync::Arc<tokio::sync::Mutex<db::SqliteDatabasePoolFactory>>,
-    settings_db: std::sync::Arc<tokio::sync::Mutex<settings_db::SettingsDb>>,
+fn allowed_chat_filter() -> teloxide::dispatching::UpdateHandler<anyhow::Error> {
+    dptree::filter_async(
+        |msg: Message, bot: AutoSend<Bot>, context: std::sync::Arc<context::HandlerContext>| async move {
+            allowlist::is_chat_allowed(&msg.chat, &bot, &context).await
+        },
+    )
+}
+
+fn detection_handler() -> teloxide::dispatching::UpdateHandler<anyhow::Error> {
+    dptree::filter_map(
+        |msg: Message, settings_db: std::sync::Arc<settings_db::SettingsDb>| {
+            // An edit without checked content still has to drop the fingerprints of the
+            // previous version
+            detection::fingerprints_to_check(&msg, &settings_db)
+                .filter(|fingerprints| !fingerprints.is_empty() || msg.edit_date().is_some())
+        },
+    )
+    .endpoint(
+        |msg: Message,
+         bot: AutoSend<Bot>,
+         fingerprints: Vec<detection::Fingerprint>,
+         context: std::sync::Arc<context::HandlerContext>| async move {
+            process_message(&context, fingerprints, msg, bot).await?;
+            anyhow::Result::Ok(())
+        },
+    )
+}
+
+async fn process_message(
+    context: &context::HandlerContext,
+    fingerprints: Vec<detection::Fingerprint>,
     msg: Message,
     bot: AutoSend<Bot>,
 ) -> anyhow::Result<()> {
-    log::debug!("Start processing the message with a forward received");
-
-    let mut pool_factory = pool_factory.lock().await;
-    match pool_factory.create(msg.chat.id.0).await {
-        Ok(client) => {
-            let forwarded_message_id = msg
-                .forward_from_message_id()
-                .ok_or_else(|| anyhow!("Cannot find a forwarded message"))?;
-            match client.check_forward_message(&forwarded_message_id).await {
-                Ok(val) => {
-                    if val {
-                        utils::send_slowpoke(msg, bot, settings_db).await?;
-                    } else if let Err(e) = client.add_forwarded_message(&forwarded_message_id).await
-                    {
-                        log::warn!("Cannot add a message to the database: {:?}", e);
+    log::debug!(
+        "Start processing the message with {} fingerprints",
+        fingerprints.len()
+    );
+
+    let pool_factory = &context.pool_factory;
+    if pool_factory.is_read_only() {
+        log::warn!("The message is not checked, since the disk is almost full");
+        return Ok(());
+    }
+
+    let client = pool_factory.create(msg.chat.id.0).await?;
+
+    let user_id = msg.from().map(|user| user.id.0);
+    let is_edit = msg.edit_date().is_some();
+    let time = utils::message_time(&msg);
+
+    // Check and insert have to be atomic within a chat, but the reply itself is sent
+    // after the chat lock is released
+    let duplicate = {
+        let _chat_guard = client.lock_chat().await;
+
+        // Fingerprints of the previous version are replaced, so an edit never matches itself.
+        // A message, which already got a slowpoke, doesn't get another one for an edit.
+        // Automatic forwards reach this point only to be remembered.
+        let is_already_detected = if is_edit {
+            client.remove_fingerprints(msg.id).await?;
+            client.has_detection(msg.id).await?
+        } else {
+            msg.is_automatic_forward()
+        };
+
+        let mut duplicate = None;
+        for fingerprint in fingerprints {
+            match client.find_fingerprint(&fingerprint, time).await? {
+                Some(fingerprint_id) => {
+                    // Known fingerprints are not rewritten, so they keep the earliest post
+                    if duplicate.is_none() && !is_already_detected {
+                        duplicate = Some((fingerprint, fingerprint_id));
                     }
                 }
-                Err(e) => log::warn!("Database error: {:?}", e),
+                None => {
+                    client
+                        .add_fingerprint(&fingerprint, msg.id, user_id, time)
+                        .await?;
+                }
             }
         }
-        Err(e) => log::warn!("Cannot create a db client: {}", e),
+        duplicate
+    };
+
+    if let Some((fingerprint, fingerprint_id)) = duplicate {
+        tracing::Span::current().record("detector", fingerprint.detector.as_str());
+        let reason = utils::SlowpokeReason::Duplicate {
+            fingerprint,
+            fingerprint_id,
+        };
+        detection::respond_to_duplicate(msg, bot, context, client, reason).await?;
     }
 
     anyhow::Result::Ok(())
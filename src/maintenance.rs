@@ -0,0 +1,301 @@
+use crate::db;
+use crate::digest;
+use crate::error_report;
+use crate::lifecycle;
+use crate::logging::TracedRequest;
+use crate::parameters;
+use crate::settings;
+use crate::settings_db;
+use crate::status;
+use teloxide::prelude::*;
+
+// Digests are sent at a given hour, so they are checked more often than the maintenance runs
+const DIGEST_CHECK_PERIODICITY: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+
+/// Starts the periodic chat database maintenance, the disk space checks, the reports of
+/// quarantined databases and the chat digests. Their failures go to the error reporter.
+pub fn spawn(
+    bot: AutoSend<Bot>,
+    pool_factory: std::sync::Arc<db::SqliteDatabasePoolFactory>,
+    settings_db: std::sync::Arc<settings_db::SettingsDb>,
+    parameters: std::sync::Arc<parameters::Parameters>,
+    runtime: std::sync::Arc<status::RuntimeStatus>,
+    error_reporter: std::sync::Arc<error_report::ErrorReporter>,
+) {
+    let digest_bot = bot.clone();
+    let digest_factory = pool_factory.clone();
+    let digest_settings_db = settings_db.clone();
+    let digest_parameters = parameters.clone();
+    let digest_error_reporter = error_reporter.clone();
+    let disk_check_factory = pool_factory.clone();
+    let disk_check_parameters = parameters.clone();
+    let disk_check_error_reporter = error_reporter.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(disk_check_parameters.disk_check_periodicity);
+        loop {
+            interval.tick().await;
+            if let Err(e) =
+                check_disk_space(&bot, &disk_check_factory, &disk_check_parameters).await
+            {
+                disk_check_error_reporter.report(e.context("Cannot check free disk space"));
+            }
+            if let Err(e) =
+                report_quarantines(&bot, &disk_check_factory, &disk_check_parameters).await
+            {
+                disk_check_error_reporter.report(e.context("Cannot report quarantined databases"));
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(DIGEST_CHECK_PERIODICITY);
+        loop {
+            interval.tick().await;
+            if let Err(e) = digest::send_due_digests(
+                &digest_bot,
+                &digest_factory,
+                &digest_settings_db,
+                &digest_parameters,
+                &digest_error_reporter,
+            )
+            .await
+            {
+                digest_error_reporter.report(e.context("Cannot send chat digests"));
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        let mut maintenance = Maintenance::default();
+        let mut interval = tokio::time::interval(parameters.message_clean_periodicity);
+        loop {
+            interval.tick().await;
+            for error in maintenance
+                .run(&pool_factory, &settings_db, &parameters)
+                .await
+            {
+                error_reporter.report(error);
+            }
+            runtime.record_maintenance();
+        }
+    });
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Retention {
+    message_age: std::time::Duration,
+    personal_data_days: u32,
+}
+
+struct ChatState {
+    maintained_at: std::time::SystemTime,
+    retention: Retention,
+    // When the oldest record has to be removed or lose its user id
+    expires_at: Option<std::time::SystemTime>,
+}
+
+/// Applies retention to chat databases and reclaims space. Chats, which didn't change since
+/// the previous run and have nothing to expire, are not opened.
+#[derive(Default)]
+pub struct Maintenance {
+    chats: std::collections::HashMap<i64, ChatState>,
+}
+
+impl Maintenance {
+    /// Returns the errors. A failed chat doesn't stop the maintenance of the others.
+    pub async fn run(
+        &mut self,
+        pool_factory: &db::SqliteDatabasePoolFactory,
+        settings_db: &settings_db::SettingsDb,
+        parameters: &parameters::Parameters,
+    ) -> Vec<anyhow::Error> {
+        let mut errors = Vec::new();
+
+        match pool_factory.remove_stale_files() {
+            Ok(removed) => {
+                for path in removed {
+                    log::info!("Removed a stale database file {}", path.display());
+                }
+            }
+            Err(e) => errors.push(e.context("Cannot remove stale database files")),
+        }
+
+        let chat_ids = match pool_factory.list_existing_chats() {
+            Ok(chat_ids) => chat_ids,
+            Err(e) => {
+                errors.push(e.context("Cannot list chats for maintenance"));
+                return errors;
+            }
+        };
+        self.chats.retain(|chat_id, _| chat_ids.contains(chat_id));
+
+        for chat_id in chat_ids {
+            if let Err(e) = self
+                .maintain_chat(chat_id, pool_factory, settings_db, parameters)
+                .await
+            {
+                errors.push(error_report::with_chat(
+                    e.context("Cannot maintain the chat"),
+                    chat_id,
+                ));
+            }
+        }
+
+        errors
+    }
+
+    async fn maintain_chat(
+        &mut self,
+        chat_id: i64,
+        pool_factory: &db::SqliteDatabasePoolFactory,
+        settings_db: &settings_db::SettingsDb,
+        parameters: &parameters::Parameters,
+    ) -> anyhow::Result<()> {
+        let namespace = settings_db::Namespace::Chat(chat_id);
+
+        // Chats, which the bot was removed from, are only waiting to be expired
+        if let Some(removed_at) = settings_db.get::<settings::RemovedAt>(namespace)? {
+            lifecycle::expire_removed_chat(
+                chat_id,
+                removed_at,
+                pool_factory,
+                settings_db,
+                parameters,
+            )
+            .await?;
+            return Ok(());
+        }
+
+        let retention = Retention {
+            message_age: settings_db
+                .get::<settings::MessageRetention>(namespace)?
+                .map_or(parameters.max_message_age, |days| {
+                    std::time::Duration::from_secs(u64::from(days) * 24 * 60 * 60)
+                }),
+            personal_data_days: settings_db
+                .get::<settings::PersonalDataRetention>(namespace)?
+                .days,
+        };
+
+        if !self.is_due(chat_id, retention, pool_factory) {
+            log::debug!("Chat with id={} has nothing to maintain", chat_id);
+            return Ok(());
+        }
+
+        pool_factory.verify_chat(chat_id).await?;
+        let client = pool_factory.create(chat_id).await?;
+        client.clean_old_messages(retention.message_age).await?;
+        if retention.personal_data_days > 0 {
+            client
+                .remove_personal_data(retention.personal_data_days)
+                .await?;
+        }
+        client.vacuum(!pool_factory.is_read_only()).await?;
+
+        let (oldest_fingerprint, oldest_personal_data) = client.oldest_record_times().await?;
+        let message_expiry = oldest_fingerprint.map(|time| unix_time(time) + retention.message_age);
+        let personal_data_expiry = oldest_personal_data
+            .filter(|_| retention.personal_data_days > 0)
+            .map(|time| {
+                unix_time(time)
+                    + std::time::Duration::from_secs(
+                        u64::from(retention.personal_data_days) * 24 * 60 * 60,
+                    )
+            });
+
+        self.chats.insert(
+            chat_id,
+            ChatState {
+                maintained_at: std::time::SystemTime::now(),
+                retention,
+                expires_at: message_expiry.into_iter().chain(personal_data_expiry).min(),
+            },
+        );
+        log::debug!("Chat with id={} maintained successfully", chat_id);
+
+        Ok(())
+    }
+
+    fn is_due(
+        &self,
+        chat_id: i64,
+        retention: Retention,
+        pool_factory: &db::SqliteDatabasePoolFactory,
+    ) -> bool {
+        let state = match self.chats.get(&chat_id) {
+            Some(state) => state,
+            None => return true,
+        };
+
+        let is_modified = pool_factory
+            .database_modified_at(chat_id)
+            .is_none_or(|modified_at| modified_at > state.maintained_at);
+        let is_expired = state
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= std::time::SystemTime::now());
+
+        is_modified || is_expired || state.retention != retention
+    }
+}
+
+fn unix_time(seconds: i64) -> std::time::SystemTime {
+    std::time::UNIX_EPOCH + std::time::Duration::from_secs(seconds.max(0) as u64)
+}
+
+// New messages are not recorded while the free space is below the threshold, so the databases
+// don't get corrupted by a full disk
+async fn check_disk_space(
+    bot: &AutoSend<Bot>,
+    pool_factory: &db::SqliteDatabasePoolFactory,
+    parameters: &parameters::Parameters,
+) -> anyhow::Result<()> {
+    let available = fs2::available_space(&parameters.chat_database_root_path)?;
+    let is_low = available < parameters.min_free_disk_space;
+    if is_low == pool_factory.is_read_only() {
+        return Ok(());
+    }
+    pool_factory.set_read_only(is_low);
+
+    let text = if is_low {
+        log::warn!(
+            "Only {} bytes of disk space are left, new messages are not recorded",
+            available
+        );
+        format!(
+            "На диске осталось {} МБ, новые сообщения не сохраняются.",
+            available / 1024 / 1024
+        )
+    } else {
+        log::info!("Disk space is available again, new messages are recorded");
+        "Место на диске освободилось, новые сообщения снова сохраняются.".to_string()
+    };
+    bot.send_message(UserId(parameters.owner_id), text)
+        .traced()
+        .await?;
+
+    Ok(())
+}
+
+async fn report_quarantines(
+    bot: &AutoSend<Bot>,
+    pool_factory: &db::SqliteDatabasePoolFactory,
+    parameters: &parameters::Parameters,
+) -> anyhow::Result<()> {
+    for report in pool_factory.take_quarantine_reports() {
+        bot.send_message(UserId(parameters.owner_id), quarantine_text(&report))
+            .traced()
+            .await?;
+    }
+
+    Ok(())
+}
+
+pub fn quarantine_text(report: &db::QuarantineReport) -> String {
+    format!(
+        "База данных чата {} повреждена ({}). Она перемещена в {}, в новую базу восстановлено записей: {}.",
+        report.chat_id,
+        report.reason,
+        report.path.display(),
+        report.salvaged_rows
+    )
+}
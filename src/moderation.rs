@@ -0,0 +1,82 @@
+use crate::context;
+use crate::db;
+use crate::error_report;
+use crate::logging::TracedRequest;
+use crate::settings;
+use crate::settings_db;
+use teloxide::prelude::*;
+use teloxide::{ApiError, RequestError};
+
+static NO_DELETE_RIGHT: &str = "У бота нет права удалять сообщения, поэтому дубликаты больше не \
+    удаляются. Выдайте боту это право и включите удаление снова командой /action.";
+
+/// Whether the bot can delete messages of other users in the chat
+pub async fn can_delete_messages(bot: &AutoSend<Bot>, chat_id: ChatId) -> anyhow::Result<bool> {
+    let me = bot.get_me().traced().await?;
+    let member = bot.get_chat_member(chat_id, me.id).traced().await?;
+    Ok(member.can_delete_messages())
+}
+
+/// Deletes the duplicate. Returns false, if the bot has no right to delete messages. Then the
+/// chat is switched back to the reply mode and the admins are told about it.
+pub async fn delete_duplicate(
+    bot: &AutoSend<Bot>,
+    settings_db: &settings_db::SettingsDb,
+    chat_id: ChatId,
+    message_id: i32,
+) -> anyhow::Result<bool> {
+    match bot.delete_message(chat_id, message_id).traced().await {
+        // Somebody was faster
+        Ok(_) | Err(RequestError::Api(ApiError::MessageToDeleteNotFound)) => Ok(true),
+        Err(RequestError::Api(ApiError::MessageCantBeDeleted)) => {
+            log::warn!(
+                "Cannot delete the message {} in the chat {}, deletion is turned off",
+                message_id,
+                chat_id
+            );
+
+            let namespace = settings_db::Namespace::Chat(chat_id.0);
+            let mut action = settings_db.get::<settings::DuplicateAction>(namespace)?;
+            action.mode = settings::ActionMode::Reply;
+            settings_db.set::<settings::DuplicateAction>(namespace, &action)?;
+
+            bot.send_message(chat_id, NO_DELETE_RIGHT).traced().await?;
+            Ok(false)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Deletes the duplicate after the delay, unless the detection is marked as a false positive
+/// by then. Failures go to the error reporter, since nobody waits for the deletion.
+pub fn schedule_deletion(
+    bot: AutoSend<Bot>,
+    context: &context::HandlerContext,
+    client: std::sync::Arc<db::ChatDatabase>,
+    chat_id: ChatId,
+    message_id: i32,
+    detection_id: Option<i64>,
+    delay: std::time::Duration,
+) {
+    let settings_db = context.settings_db.clone();
+    let error_reporter = context.error_reporter.clone();
+    context.tasks.spawn("delete_duplicate", delay, async move {
+        let result = async {
+            if let Some(detection_id) = detection_id {
+                if client.is_false_positive(detection_id).await? {
+                    return Ok(());
+                }
+            }
+            delete_duplicate(&bot, &settings_db, chat_id, message_id).await?;
+            anyhow::Result::<()>::Ok(())
+        }
+        .await;
+
+        if let Err(e) = result {
+            error_reporter.report(error_report::with_chat(
+                e.context(format!("Cannot delete the duplicate {}", message_id)),
+                chat_id.0,
+            ));
+        }
+    });
+}
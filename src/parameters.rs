@@ -3,10 +3,20 @@ pub struct Parameters {
     pub owner_id: u64,
     pub settings_database_path: std::path::PathBuf,
     pub chat_database_root_path: std::path::PathBuf,
+    pub backup_path: std::path::PathBuf,
     pub max_database_connections_count: u32,
     pub max_message_age: std::time::Duration,
     pub message_clean_periodicity: std::time::Duration,
+    pub min_free_disk_space: u64,
+    pub disk_check_periodicity: std::time::Duration,
     pub is_webhook_mode_enabled: bool,
+    pub is_allowlist_mode_enabled: bool,
+    pub chat_approval_timeout: std::time::Duration,
+    pub removed_chat_grace_period: std::time::Duration,
+    pub is_removed_chat_archive_enabled: bool,
+    pub archive_path: std::path::PathBuf,
+    pub otlp_endpoint: Option<String>,
+    pub error_report_periodicity: std::time::Duration,
 }
 
 impl Parameters {
@@ -18,15 +28,11 @@ impl Parameters {
             .parse()
             .expect("Cannot parse as u64");
 
-        let settings_database_path: std::path::PathBuf = std::env::var("SETTINGS_DATABASE_PATH")
-            .expect("SETTINGS_DATABASE_PATH is not specified")
-            .parse()
-            .expect("Cannot parse as a filepath");
+        let settings_database_path = settings_database_path();
 
-        let chat_database_root_path: std::path::PathBuf = std::env::var("CHAT_DATABASE_PATH")
-            .expect("CHAT_DATABASE_PATH is not specified")
-            .parse()
-            .expect("Cannot parse as a filepath");
+        let chat_database_root_path = chat_database_root_path();
+
+        let backup_path = backup_path();
 
         let max_database_connections_count: u32 = std::env::var("MAX_DB_CONNECTIONS")
             .unwrap_or_else(|_| "5".to_string())
@@ -55,6 +61,20 @@ impl Parameters {
                 .expect("Cannot parse provided time as seconds"),
         );
 
+        let min_free_disk_space: u64 = std::env::var("MIN_FREE_DISK_SPACE_IN_MB")
+            .unwrap_or_else(|_| "100".to_string())
+            .parse::<u64>()
+            .expect("MIN_FREE_DISK_SPACE_IN_MB value has to be an unsigned integer")
+            * 1024
+            * 1024;
+
+        let disk_check_periodicity = std::time::Duration::from_secs(
+            std::env::var("DISK_CHECK_PERIODICITY_IN_SECONDS")
+                .unwrap_or_else(|_| "60".to_string())
+                .parse()
+                .expect("Cannot parse provided time as seconds"),
+        );
+
         let is_webhook_mode_enabled: bool = std::env::var("WEBHOOK_MODE")
             .unwrap_or_else(|_| "false".to_string())
             .parse()
@@ -62,15 +82,129 @@ impl Parameters {
                 "Cannot convert WEBHOOK_MODE to bool. Applicable values are only \"true\" or \"false\"",
             );
 
+        let is_allowlist_mode_enabled: bool = std::env::var("ALLOWLIST_MODE")
+            .unwrap_or_else(|_| "false".to_string())
+            .parse()
+            .expect(
+                "Cannot convert ALLOWLIST_MODE to bool. Applicable values are only \"true\" or \"false\"",
+            );
+
+        let chat_approval_timeout = std::time::Duration::from_secs(
+            std::env::var("CHAT_APPROVAL_TIMEOUT_IN_SECONDS")
+                .unwrap_or_else(|_| {
+                    std::time::Duration::from_secs(24 * 60 * 60)
+                        .as_secs()
+                        .to_string()
+                })
+                .parse()
+                .expect("Cannot parse provided time as seconds"),
+        );
+
+        let removed_chat_grace_period = std::time::Duration::from_secs(
+            std::env::var("REMOVED_CHAT_GRACE_PERIOD_IN_SECONDS")
+                .unwrap_or_else(|_| {
+                    std::time::Duration::from_secs(30 * 24 * 60 * 60)
+                        .as_secs()
+                        .to_string()
+                })
+                .parse()
+                .expect("Cannot parse provided time as seconds"),
+        );
+
+        let is_removed_chat_archive_enabled: bool = std::env::var("ARCHIVE_REMOVED_CHATS")
+            .unwrap_or_else(|_| "true".to_string())
+            .parse()
+            .expect(
+                "Cannot convert ARCHIVE_REMOVED_CHATS to bool. Applicable values are only \"true\" or \"false\"",
+            );
+
+        let archive_path = std::env::var("ARCHIVE_PATH")
+            .unwrap_or_else(|_| "archive".to_string())
+            .parse()
+            .expect("Cannot parse as a filepath");
+
+        // Spans are exported only to a configured collector, e.g. "http://localhost:4317"
+        let otlp_endpoint = std::env::var("OTLP_ENDPOINT").ok();
+
+        let error_report_periodicity = std::time::Duration::from_secs(
+            std::env::var("ERROR_REPORT_PERIODICITY_IN_SECONDS")
+                .unwrap_or_else(|_| {
+                    std::time::Duration::from_secs(60 * 60)
+                        .as_secs()
+                        .to_string()
+                })
+                .parse()
+                .expect("Cannot parse provided time as seconds"),
+        );
+
         Self {
             bot_name,
             owner_id,
             settings_database_path,
             chat_database_root_path,
+            backup_path,
             max_database_connections_count,
             max_message_age,
             message_clean_periodicity,
+            min_free_disk_space,
+            disk_check_periodicity,
             is_webhook_mode_enabled,
+            is_allowlist_mode_enabled,
+            chat_approval_timeout,
+            removed_chat_grace_period,
+            is_removed_chat_archive_enabled,
+            archive_path,
+            otlp_endpoint,
+            error_report_periodicity,
+        }
+    }
+
+    /// Parameters for running the handlers without Telegram, e.g. in the replay mode.
+    /// All data is kept under `data_path`.
+    pub fn offline(data_path: &std::path::Path, owner_id: u64) -> Self {
+        Self {
+            bot_name: "slowpoke".to_string(),
+            owner_id,
+            settings_database_path: data_path.join("settings"),
+            chat_database_root_path: data_path.join("chats"),
+            backup_path: data_path.join("backups"),
+            max_database_connections_count: 1,
+            max_message_age: std::time::Duration::from_secs(3 * 24 * 60 * 60),
+            message_clean_periodicity: std::time::Duration::from_secs(24 * 60 * 60),
+            min_free_disk_space: 100 * 1024 * 1024,
+            disk_check_periodicity: std::time::Duration::from_secs(60),
+            is_webhook_mode_enabled: false,
+            is_allowlist_mode_enabled: false,
+            chat_approval_timeout: std::time::Duration::from_secs(24 * 60 * 60),
+            removed_chat_grace_period: std::time::Duration::from_secs(30 * 24 * 60 * 60),
+            is_removed_chat_archive_enabled: true,
+            archive_path: data_path.join("archive"),
+            otlp_endpoint: None,
+            error_report_periodicity: std::time::Duration::from_secs(60 * 60),
         }
     }
 }
+
+// Storage locations are also needed by the command line tools, which don't talk to Telegram and
+// so shouldn't require the rest of the parameters
+
+pub fn settings_database_path() -> std::path::PathBuf {
+    std::env::var("SETTINGS_DATABASE_PATH")
+        .expect("SETTINGS_DATABASE_PATH is not specified")
+        .parse()
+        .expect("Cannot parse as a filepath")
+}
+
+pub fn chat_database_root_path() -> std::path::PathBuf {
+    std::env::var("CHAT_DATABASE_PATH")
+        .expect("CHAT_DATABASE_PATH is not specified")
+        .parse()
+        .expect("Cannot parse as a filepath")
+}
+
+pub fn backup_path() -> std::path::PathBuf {
+    std::env::var("BACKUP_PATH")
+        .unwrap_or_else(|_| "backups".to_string())
+        .parse()
+        .expect("Cannot parse as a filepath")
+}
@@ -0,0 +1,89 @@
+use crate::db;
+use crate::error_report;
+use crate::settings;
+use crate::settings_db;
+
+pub fn is_opted_out(settings_db: &settings_db::SettingsDb, user_id: u64) -> bool {
+    match settings_db.get::<settings::OptedOut>(settings_db::Namespace::User(user_id)) {
+        Ok(is_opted_out) => is_opted_out,
+        Err(e) => {
+            log::warn!("Cannot get whether a user opted out: {:?}", e);
+            false
+        }
+    }
+}
+
+// Every user has a separate value, so concurrent changes don't overwrite each other. Users, who
+// opted in, have nothing stored.
+pub fn set_opted_out(
+    settings_db: &settings_db::SettingsDb,
+    user_id: u64,
+    is_opted_out: bool,
+) -> anyhow::Result<()> {
+    let namespace = settings_db::Namespace::User(user_id);
+    if is_opted_out {
+        settings_db.set::<settings::OptedOut>(namespace, &true)
+    } else {
+        settings_db.reset::<settings::OptedOut>(namespace)
+    }
+}
+
+pub struct ForgetStats {
+    pub removed: u64,
+    /// Failures of single chats, the other chats are still cleaned
+    pub errors: Vec<anyhow::Error>,
+}
+
+/// Removes records and exemptions of the user from all chats. Exemptions are scrubbed even if
+/// records of some chats cannot be removed.
+pub async fn forget_user(
+    pool_factory: &db::SqliteDatabasePoolFactory,
+    settings_db: &settings_db::SettingsDb,
+    user_id: u64,
+) -> anyhow::Result<ForgetStats> {
+    let mut stats = ForgetStats {
+        removed: 0,
+        errors: Vec::new(),
+    };
+
+    let chat_ids = pool_factory.list_existing_chats().unwrap_or_else(|e| {
+        stats
+            .errors
+            .push(e.context("Cannot list chats to remove records of a user"));
+        Vec::new()
+    });
+    for chat_id in chat_ids {
+        let removed: anyhow::Result<u64> = async {
+            let client = pool_factory.create(chat_id).await?;
+            Ok(client.forget_user(user_id).await?)
+        }
+        .await;
+        match removed {
+            Ok(removed) => stats.removed += removed,
+            Err(e) => stats.errors.push(error_report::with_chat(
+                e.context("Cannot remove records of a user"),
+                chat_id,
+            )),
+        }
+    }
+
+    for chat_id in settings_db.chats_with::<settings::Exemptions>()? {
+        if let Err(e) = settings_db
+            .update::<settings::Exemptions>(settings_db::Namespace::Chat(chat_id), |exemptions| {
+                exemptions.users.retain(|user| *user != user_id)
+            })
+        {
+            stats.errors.push(error_report::with_chat(
+                e.context("Cannot remove exemptions of a user"),
+                chat_id,
+            ));
+        }
+    }
+
+    log::info!(
+        "{} records of a user were removed on request, {} chats failed",
+        stats.removed,
+        stats.errors.len()
+    );
+    Ok(stats)
+}
@@ -0,0 +1,369 @@
+use crate::background;
+use crate::db;
+use crate::error_report;
+use crate::journal;
+use crate::parameters;
+use crate::reply_policy;
+use crate::settings;
+use crate::settings_db;
+use crate::status;
+use std::io::{BufRead, Write};
+use teloxide::prelude::*;
+
+// Methods, which don't change anything visible to users, so they are not reported
+static READ_ONLY_METHODS: &[&str] = &[
+    "getMe",
+    "getChat",
+    "getChatMember",
+    "getChatAdministrators",
+    "getFile",
+];
+
+// Methods, which return a sent message
+static MESSAGE_METHODS: &[&str] = &[
+    "sendMessage",
+    "sendPhoto",
+    "sendAnimation",
+    "sendDocument",
+    "sendSticker",
+    "sendVideo",
+    "forwardMessage",
+    "editMessageText",
+    "editMessageCaption",
+    "editMessageReplyMarkup",
+];
+
+#[derive(serde::Serialize)]
+struct ApiCall {
+    method: String,
+    params: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Stand-in for the Telegram Bot API, which records all requests and answers them with
+/// plausible results.
+#[derive(Default)]
+struct RecordingApi {
+    calls: std::sync::Mutex<Vec<ApiCall>>,
+    last_message_id: std::sync::atomic::AtomicI32,
+}
+
+impl RecordingApi {
+    fn take(&self) -> Vec<ApiCall> {
+        std::mem::take(&mut *self.calls.lock().expect("Recording API mutex is poisoned"))
+    }
+}
+
+#[derive(serde::Serialize)]
+struct ReportEntry {
+    update_id: i32,
+    calls: Vec<ApiCall>,
+    reasons: Vec<journal::JournalEntry>,
+    scheduled: Vec<background::ScheduledTask>,
+}
+
+pub struct ReplayStats {
+    pub updates: usize,
+    pub skipped: usize,
+    pub updates_with_replies: usize,
+}
+
+/// Runs updates from a JSONL file through the bot handler and writes a JSONL report with every
+/// request the bot would have sent to Telegram and the reasons of the slowpoke replies.
+///
+/// Without `data_path` the replay starts with empty storage, which is removed afterwards.
+pub async fn replay(
+    input: &std::path::Path,
+    output: &mut dyn Write,
+    data_path: Option<&std::path::Path>,
+    owner_id: u64,
+) -> anyhow::Result<ReplayStats> {
+    let temporary_path =
+        std::env::temp_dir().join(format!("slowpoke-replay-{}", std::process::id()));
+    if data_path.is_none() && temporary_path.exists() {
+        std::fs::remove_dir_all(&temporary_path)?;
+    }
+
+    let result = replay_with_storage(
+        input,
+        output,
+        data_path.unwrap_or(&temporary_path),
+        owner_id,
+    )
+    .await;
+
+    if data_path.is_none() {
+        if let Err(e) = std::fs::remove_dir_all(&temporary_path) {
+            log::warn!("Cannot remove the temporary replay data: {}", e);
+        }
+    }
+
+    result
+}
+
+async fn replay_with_storage(
+    input: &std::path::Path,
+    output: &mut dyn Write,
+    data_path: &std::path::Path,
+    owner_id: u64,
+) -> anyhow::Result<ReplayStats> {
+    let parameters = std::sync::Arc::new(parameters::Parameters::offline(data_path, owner_id));
+    std::fs::create_dir_all(&parameters.chat_database_root_path)?;
+
+    let settings_db = std::sync::Arc::new(settings_db::SettingsDb::new(
+        parameters.settings_database_path.as_path(),
+    )?);
+    // Empty storage has no reply image, so all slowpokes would turn into apologies
+    if settings_db
+        .get::<settings::ReplyImage>(settings_db::Namespace::Global)?
+        .is_none()
+    {
+        settings_db.set::<settings::ReplyImage>(
+            settings_db::Namespace::Global,
+            &Some(settings::ImageFileId {
+                file_id: "replay".to_string(),
+            }),
+        )?;
+    }
+
+    let pool_factory = std::sync::Arc::new(db::SqliteDatabasePoolFactory::new(
+        parameters.chat_database_root_path.clone(),
+        parameters.max_database_connections_count,
+    ));
+    let journal = std::sync::Arc::new(journal::ReplyJournal::recording());
+
+    let api = std::sync::Arc::new(RecordingApi::default());
+    let app = axum::Router::new()
+        .route("/:token/:method", axum::routing::post(api_request))
+        .layer(tower_http::add_extension::AddExtensionLayer::new(
+            api.clone(),
+        ));
+    let server = axum::Server::bind(&std::net::SocketAddr::from(([127, 0, 0, 1], 0)))
+        .serve(app.into_make_service());
+    let api_url = format!("http://{}/", server.local_addr());
+    let server = tokio::spawn(server);
+
+    let bot = Bot::new("replay").set_api_url(api_url.parse()?).auto_send();
+    let me = bot.get_me().await?;
+    api.take();
+
+    let handler = crate::build_handler();
+    let tasks = std::sync::Arc::new(background::BackgroundTasks::deferring());
+    let error_reporter = std::sync::Arc::new(error_report::ErrorReporter::new(
+        bot.clone(),
+        owner_id,
+        parameters.error_report_periodicity,
+        tasks.clone(),
+    ));
+    let dependencies = dptree::deps![
+        bot,
+        me,
+        pool_factory,
+        settings_db,
+        parameters.clone(),
+        journal.clone(),
+        std::sync::Arc::new(reply_policy::ReplyLimiter::default()),
+        error_reporter,
+        std::sync::Arc::new(status::RuntimeStatus::new()),
+        tasks.clone()
+    ];
+
+    let mut stats = ReplayStats {
+        updates: 0,
+        skipped: 0,
+        updates_with_replies: 0,
+    };
+
+    for (line_number, line) in std::io::BufReader::new(std::fs::File::open(input)?)
+        .lines()
+        .enumerate()
+    {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let update: Update = match serde_json::from_str(&line) {
+            Ok(update) => update,
+            Err(e) => {
+                log::error!(
+                    "Cannot parse an update on the line {}: {}",
+                    line_number + 1,
+                    e
+                );
+                stats.skipped += 1;
+                continue;
+            }
+        };
+        stats.updates += 1;
+
+        let update_id = update.id;
+        let mut update_dependencies = dependencies.clone();
+        update_dependencies.insert(update);
+
+        if let std::ops::ControlFlow::Break(Err(e)) = handler.dispatch(update_dependencies).await {
+            log::warn!("Error while handling the update {}: {:?}", update_id, e);
+        }
+
+        let scheduled = settle_tasks(&tasks).await;
+        let entry = ReportEntry {
+            update_id,
+            calls: api.take(),
+            reasons: journal.take(),
+            scheduled,
+        };
+        if !entry.calls.is_empty() || !entry.reasons.is_empty() || !entry.scheduled.is_empty() {
+            stats.updates_with_replies += 1;
+            serde_json::to_writer(&mut *output, &entry)?;
+            writeln!(output)?;
+        }
+    }
+
+    server.abort();
+
+    Ok(stats)
+}
+
+// Tasks without a delay are run after the handler, so their requests are reported with the
+// update in a stable order. Delayed tasks would wait for real time, so they are only listed.
+async fn settle_tasks(tasks: &background::BackgroundTasks) -> Vec<background::ScheduledTask> {
+    let mut scheduled = Vec::new();
+
+    // Tasks may start new ones, e.g. an error digest
+    loop {
+        let started = tasks.take();
+        if started.is_empty() {
+            break;
+        }
+        for task in started {
+            if task.delay.is_zero() {
+                task.run().await;
+            } else {
+                scheduled.push(task.cancel());
+            }
+        }
+    }
+
+    scheduled
+}
+
+async fn api_request(
+    axum::extract::Path((_, method)): axum::extract::Path<(String, String)>,
+    axum::extract::Extension(api): axum::extract::Extension<std::sync::Arc<RecordingApi>>,
+    request: axum::http::Request<axum::body::Body>,
+) -> axum::Json<serde_json::Value> {
+    // Teloxide uses names like `SendMessage`, while the documentation uses `sendMessage`
+    let mut characters = method.chars();
+    let method: String = characters
+        .next()
+        .map(|first| first.to_ascii_lowercase())
+        .into_iter()
+        .chain(characters)
+        .collect();
+
+    let params = match parse_params(request).await {
+        Ok(params) => params,
+        Err(e) => {
+            log::warn!("Cannot parse parameters of {}: {}", method, e);
+            Default::default()
+        }
+    };
+
+    let result = if method == "getMe" {
+        serde_json::json!({
+            "id": 1,
+            "is_bot": true,
+            "first_name": "Slowpoke",
+            "username": "slowpoke_replay_bot",
+            "can_join_groups": true,
+            "can_read_all_group_messages": true,
+            "supports_inline_queries": true,
+        })
+    } else if method == "getChatMember" {
+        // Nobody has administrator rights in the replay
+        serde_json::json!({
+            "status": "member",
+            "user": {
+                "id": params.get("user_id").cloned().unwrap_or_default(),
+                "is_bot": false,
+                "first_name": "replay",
+            },
+        })
+    } else if method == "getChatAdministrators" {
+        serde_json::Value::Array(Vec::new())
+    } else if method == "getFile" {
+        serde_json::json!({
+            "file_id": params.get("file_id").cloned().unwrap_or_default(),
+            "file_unique_id": "replay",
+            "file_size": 0,
+            "file_path": "replay",
+        })
+    } else if method == "copyMessage" {
+        let message_id = api
+            .last_message_id
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+            + 1;
+        serde_json::json!({ "message_id": message_id })
+    } else if MESSAGE_METHODS.contains(&method.as_str()) {
+        let message_id = api
+            .last_message_id
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+            + 1;
+        let chat_id = params
+            .get("chat_id")
+            .and_then(|chat_id| match chat_id {
+                serde_json::Value::Number(number) => number.as_i64(),
+                serde_json::Value::String(string) => string.parse().ok(),
+                _ => None,
+            })
+            .unwrap_or_default();
+        serde_json::json!({
+            "message_id": message_id,
+            "date": 0,
+            "chat": {"id": chat_id, "type": "group", "title": "replay"},
+            "text": "replay",
+        })
+    } else {
+        serde_json::Value::Bool(true)
+    };
+
+    if !READ_ONLY_METHODS.contains(&method.as_str()) {
+        api.calls
+            .lock()
+            .expect("Recording API mutex is poisoned")
+            .push(ApiCall { method, params });
+    }
+
+    axum::Json(serde_json::json!({ "ok": true, "result": result }))
+}
+
+// Teloxide sends JSON for most methods, but multipart forms for methods with files
+async fn parse_params(
+    request: axum::http::Request<axum::body::Body>,
+) -> anyhow::Result<serde_json::Map<String, serde_json::Value>> {
+    let is_multipart = request
+        .headers()
+        .get(axum::http::header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("multipart/form-data"));
+
+    let mut request = axum::extract::RequestParts::new(request);
+
+    if is_multipart {
+        let mut multipart: axum::extract::Multipart = request.extract().await?;
+        let mut params = serde_json::Map::new();
+        while let Some(field) = multipart.next_field().await? {
+            let name = field.name().unwrap_or_default().to_string();
+            let text = field.text().await?;
+            let value = serde_json::from_str(&text).unwrap_or(serde_json::Value::String(text));
+            params.insert(name, value);
+        }
+        Ok(params)
+    } else {
+        let body: axum::body::Bytes = request.extract().await?;
+        if body.is_empty() {
+            Ok(Default::default())
+        } else {
+            Ok(serde_json::from_slice(&body)?)
+        }
+    }
+}
@@ -0,0 +1,316 @@
+use crate::settings;
+use crate::settings_db;
+
+const COMMAND_LIMIT_WINDOW: chrono::Duration = chrono::Duration::hours(1);
+
+/// Why a slowpoke was not sent
+#[derive(Debug)]
+pub enum Suppression {
+    QuietHours,
+    Cooldown,
+    DailyLimit,
+    CommandLimit,
+}
+
+impl std::fmt::Display for Suppression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Suppression::QuietHours => write!(f, "quiet_hours"),
+            Suppression::Cooldown => write!(f, "cooldown"),
+            Suppression::DailyLimit => write!(f, "daily_limit"),
+            Suppression::CommandLimit => write!(f, "command_limit"),
+        }
+    }
+}
+
+/// Applies chat reply policies. The state is kept in memory, so the limits start over after
+/// a restart. Callers pass the time of the message, which is replied to.
+#[derive(Default)]
+pub struct ReplyLimiter {
+    last_reply: dashmap::DashMap<i64, chrono::DateTime<chrono::Utc>>,
+    daily_replies: dashmap::DashMap<i64, (chrono::NaiveDate, u32)>,
+    slowpoke_commands:
+        dashmap::DashMap<(i64, u64), std::collections::VecDeque<chrono::DateTime<chrono::Utc>>>,
+}
+
+impl ReplyLimiter {
+    /// Checks whether a reply to a duplicate can be sent at `now` and counts it, if so.
+    pub fn allow_duplicate_reply(
+        &self,
+        settings_db: &settings_db::SettingsDb,
+        chat_id: i64,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> anyhow::Result<Result<(), Suppression>> {
+        let (policy, local_time) = Self::chat_policy(settings_db, chat_id, now)?;
+
+        if let Some(quiet_hours) = &policy.quiet_hours {
+            if quiet_hours.contains(local_time.time())? {
+                return Ok(Err(Suppression::QuietHours));
+            }
+        }
+
+        let min_interval = chrono::Duration::seconds(policy.min_reply_interval_seconds as i64);
+        if let Some(last_reply) = self.last_reply.get(&chat_id) {
+            if now - *last_reply < min_interval {
+                return Ok(Err(Suppression::Cooldown));
+            }
+        }
+
+        if let Err(suppression) = self.count_daily_reply(&policy, chat_id, local_time) {
+            return Ok(Err(suppression));
+        }
+        self.last_reply.insert(chat_id, now);
+
+        Ok(Ok(()))
+    }
+
+    /// Checks whether the user can get a slowpoke by the command at `now` and counts it, if so.
+    pub fn allow_slowpoke_command(
+        &self,
+        settings_db: &settings_db::SettingsDb,
+        chat_id: i64,
+        user_id: u64,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> anyhow::Result<Result<(), Suppression>> {
+        let (policy, local_time) = Self::chat_policy(settings_db, chat_id, now)?;
+
+        let mut commands = self
+            .slowpoke_commands
+            .entry((chat_id, user_id))
+            .or_default();
+        while let Some(time) = commands.front() {
+            if now - *time < COMMAND_LIMIT_WINDOW {
+                break;
+            }
+            commands.pop_front();
+        }
+
+        let limit = policy.slowpoke_commands_per_user_per_hour as usize;
+        if limit != 0 && commands.len() >= limit {
+            return Ok(Err(Suppression::CommandLimit));
+        }
+
+        if let Err(suppression) = self.count_daily_reply(&policy, chat_id, local_time) {
+            return Ok(Err(suppression));
+        }
+        commands.push_back(now);
+
+        Ok(Ok(()))
+    }
+
+    fn count_daily_reply(
+        &self,
+        policy: &settings::ReplyPolicyValue,
+        chat_id: i64,
+        local_time: chrono::DateTime<chrono_tz::Tz>,
+    ) -> Result<(), Suppression> {
+        let today = local_time.date_naive();
+        let mut daily_replies = self.daily_replies.entry(chat_id).or_insert((today, 0));
+        if daily_replies.0 != today {
+            *daily_replies = (today, 0);
+        }
+
+        if policy.max_replies_per_day != 0 && daily_replies.1 >= policy.max_replies_per_day {
+            return Err(Suppression::DailyLimit);
+        }
+        daily_replies.1 += 1;
+
+        Ok(())
+    }
+
+    fn chat_policy(
+        settings_db: &settings_db::SettingsDb,
+        chat_id: i64,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> anyhow::Result<(settings::ReplyPolicyValue, chrono::DateTime<chrono_tz::Tz>)> {
+        let namespace = settings_db::Namespace::Chat(chat_id);
+        let policy = settings_db.get::<settings::ReplyPolicy>(namespace)?;
+        let timezone = chat_timezone(settings_db, chat_id)?;
+
+        Ok((policy, now.with_timezone(&timezone)))
+    }
+}
+
+pub fn chat_timezone(
+    settings_db: &settings_db::SettingsDb,
+    chat_id: i64,
+) -> anyhow::Result<chrono_tz::Tz> {
+    let timezone =
+        settings_db.get::<settings::ChatTimezone>(settings_db::Namespace::Chat(chat_id))?;
+    if timezone.is_empty() {
+        Ok(chrono_tz::UTC)
+    } else {
+        settings::ChatTimezone::parse(&timezone)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHAT_ID: i64 = -1001;
+
+    fn now() -> chrono::DateTime<chrono::Utc> {
+        chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap()
+    }
+
+    fn settings_db(policy: settings::ReplyPolicyValue) -> settings_db::SettingsDb {
+        let settings_db = settings_db::SettingsDb::temporary().unwrap();
+        settings_db
+            .set::<settings::ReplyPolicy>(settings_db::Namespace::Chat(CHAT_ID), &policy)
+            .unwrap();
+        settings_db
+    }
+
+    #[test]
+    fn replies_are_unlimited_by_default() {
+        let settings_db = settings_db(Default::default());
+        let limiter = ReplyLimiter::default();
+        for _ in 0..100 {
+            assert!(matches!(
+                limiter.allow_duplicate_reply(&settings_db, CHAT_ID, now()),
+                Ok(Ok(()))
+            ));
+            assert!(matches!(
+                limiter.allow_slowpoke_command(&settings_db, CHAT_ID, 1, now()),
+                Ok(Ok(()))
+            ));
+        }
+    }
+
+    #[test]
+    fn replies_are_limited_per_day() {
+        let settings_db = settings_db(settings::ReplyPolicyValue {
+            max_replies_per_day: 2,
+            ..Default::default()
+        });
+        let limiter = ReplyLimiter::default();
+        assert!(matches!(
+            limiter.allow_duplicate_reply(&settings_db, CHAT_ID, now()),
+            Ok(Ok(()))
+        ));
+        // Slowpokes by the command count towards the same limit
+        assert!(matches!(
+            limiter.allow_slowpoke_command(&settings_db, CHAT_ID, 1, now()),
+            Ok(Ok(()))
+        ));
+        assert!(matches!(
+            limiter.allow_duplicate_reply(&settings_db, CHAT_ID, now()),
+            Ok(Err(Suppression::DailyLimit))
+        ));
+        assert!(matches!(
+            limiter.allow_slowpoke_command(&settings_db, CHAT_ID, 1, now()),
+            Ok(Err(Suppression::DailyLimit))
+        ));
+
+        // Other chats have their own limits
+        assert!(matches!(
+            limiter.allow_duplicate_reply(&settings_db, CHAT_ID - 1, now()),
+            Ok(Ok(()))
+        ));
+    }
+
+    #[test]
+    fn replies_to_duplicates_wait_for_the_cooldown() {
+        let settings_db = settings_db(settings::ReplyPolicyValue {
+            min_reply_interval_seconds: 60 * 60,
+            ..Default::default()
+        });
+        let limiter = ReplyLimiter::default();
+        assert!(matches!(
+            limiter.allow_duplicate_reply(&settings_db, CHAT_ID, now()),
+            Ok(Ok(()))
+        ));
+        assert!(matches!(
+            limiter.allow_duplicate_reply(&settings_db, CHAT_ID, now()),
+            Ok(Err(Suppression::Cooldown))
+        ));
+        // The cooldown is only for replies to duplicates
+        assert!(matches!(
+            limiter.allow_slowpoke_command(&settings_db, CHAT_ID, 1, now()),
+            Ok(Ok(()))
+        ));
+    }
+
+    #[test]
+    fn suppressed_replies_are_not_counted() {
+        let settings_db = settings_db(settings::ReplyPolicyValue {
+            min_reply_interval_seconds: 60 * 60,
+            max_replies_per_day: 2,
+            ..Default::default()
+        });
+        let limiter = ReplyLimiter::default();
+        for _ in 0..3 {
+            let _ = limiter.allow_duplicate_reply(&settings_db, CHAT_ID, now());
+        }
+        assert!(matches!(
+            limiter.allow_slowpoke_command(&settings_db, CHAT_ID, 1, now()),
+            Ok(Ok(()))
+        ));
+    }
+
+    #[test]
+    fn slowpoke_commands_are_limited_per_user() {
+        let settings_db = settings_db(settings::ReplyPolicyValue {
+            slowpoke_commands_per_user_per_hour: 2,
+            ..Default::default()
+        });
+        let limiter = ReplyLimiter::default();
+        for _ in 0..2 {
+            assert!(matches!(
+                limiter.allow_slowpoke_command(&settings_db, CHAT_ID, 1, now()),
+                Ok(Ok(()))
+            ));
+        }
+        assert!(matches!(
+            limiter.allow_slowpoke_command(&settings_db, CHAT_ID, 1, now()),
+            Ok(Err(Suppression::CommandLimit))
+        ));
+
+        assert!(matches!(
+            limiter.allow_slowpoke_command(&settings_db, CHAT_ID, 2, now()),
+            Ok(Ok(()))
+        ));
+        assert!(matches!(
+            limiter.allow_slowpoke_command(&settings_db, CHAT_ID - 1, 1, now()),
+            Ok(Ok(()))
+        ));
+        // The command limit doesn't affect replies to duplicates
+        assert!(matches!(
+            limiter.allow_duplicate_reply(&settings_db, CHAT_ID, now()),
+            Ok(Ok(()))
+        ));
+    }
+
+    #[test]
+    fn limits_are_counted_from_the_message_time() {
+        let settings_db = settings_db(settings::ReplyPolicyValue {
+            min_reply_interval_seconds: 60,
+            max_replies_per_day: 2,
+            ..Default::default()
+        });
+        let limiter = ReplyLimiter::default();
+        let later = |seconds| now() + chrono::Duration::seconds(seconds);
+
+        assert!(matches!(
+            limiter.allow_duplicate_reply(&settings_db, CHAT_ID, now()),
+            Ok(Ok(()))
+        ));
+        assert!(matches!(
+            limiter.allow_duplicate_reply(&settings_db, CHAT_ID, later(59)),
+            Ok(Err(Suppression::Cooldown))
+        ));
+        assert!(matches!(
+            limiter.allow_duplicate_reply(&settings_db, CHAT_ID, later(60)),
+            Ok(Ok(()))
+        ));
+        assert!(matches!(
+            limiter.allow_duplicate_reply(&settings_db, CHAT_ID, later(120)),
+            Ok(Err(Suppression::DailyLimit))
+        ));
+        assert!(matches!(
+            limiter.allow_duplicate_reply(&settings_db, CHAT_ID, later(24 * 60 * 60)),
+            Ok(Ok(()))
+        ));
+    }
+}
@@ -0,0 +1,543 @@
+use crate::settings_db::{Scope, Setting, SettingDescriptor};
+
+/// All known settings. A setting has to be registered here to be visible for listing and editing.
+pub static ALL: &[SettingDescriptor] = &[
+    SettingDescriptor::of::<ReplyImage>(),
+    SettingDescriptor::of::<AlternateImages>(),
+    SettingDescriptor::of::<ShadowMode>(),
+    SettingDescriptor::of::<ChatTimezone>(),
+    SettingDescriptor::of::<ReplyPolicy>(),
+    SettingDescriptor::of::<ChatApproval>(),
+    SettingDescriptor::of::<Exemptions>(),
+    SettingDescriptor::of::<DuplicateAction>(),
+    SettingDescriptor::of::<ChannelAlertChat>(),
+    SettingDescriptor::of::<LinkedChannel>(),
+    SettingDescriptor::of::<LinkedChannelId>(),
+    SettingDescriptor::of::<RemovedAt>(),
+    SettingDescriptor::of::<OptedOut>(),
+    SettingDescriptor::of::<PersonalDataRetention>(),
+    SettingDescriptor::of::<MessageRetention>(),
+    SettingDescriptor::of::<Digest>(),
+    SettingDescriptor::of::<DigestSentAt>(),
+];
+
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ImageFileId {
+    pub file_id: std::string::String,
+}
+
+pub struct ReplyImage;
+
+impl Setting for ReplyImage {
+    const KEY: &'static str = "reply_image";
+    const SCOPE: Scope = Scope::Global;
+    const DESCRIPTION: &'static str = "Image, which is sent as a slowpoke reply";
+
+    type Value = Option<ImageFileId>;
+
+    fn validate(value: &Self::Value) -> anyhow::Result<()> {
+        match value {
+            Some(image) if image.file_id.is_empty() => bail!("Image file id cannot be empty"),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Telegram accepts at most 50 inline results, one of them is the reply image
+pub const MAX_ALTERNATE_IMAGES: usize = 49;
+
+pub struct AlternateImages;
+
+impl Setting for AlternateImages {
+    const KEY: &'static str = "alternate_images";
+    const SCOPE: Scope = Scope::Global;
+    const DESCRIPTION: &'static str =
+        "Additional images, which are offered together with the reply image in the inline mode";
+
+    type Value = Vec<ImageFileId>;
+
+    fn validate(value: &Self::Value) -> anyhow::Result<()> {
+        if value.iter().any(|image| image.file_id.is_empty()) {
+            bail!("Image file id cannot be empty");
+        }
+        if value.len() > MAX_ALTERNATE_IMAGES {
+            bail!("There can be at most {} images", MAX_ALTERNATE_IMAGES);
+        }
+        Ok(())
+    }
+}
+
+pub struct ShadowMode;
+
+impl Setting for ShadowMode {
+    const KEY: &'static str = "shadow_mode";
+    const SCOPE: Scope = Scope::Chat;
+    const DESCRIPTION: &'static str =
+        "Duplicates are detected and recorded, but slowpokes are not sent";
+
+    type Value = bool;
+}
+
+pub struct ChatTimezone;
+
+impl ChatTimezone {
+    pub fn parse(value: &str) -> anyhow::Result<chrono_tz::Tz> {
+        value
+            .parse()
+            .map_err(|e| anyhow!("Unknown timezone {}: {}", value, e))
+    }
+}
+
+impl Setting for ChatTimezone {
+    const KEY: &'static str = "timezone";
+    const SCOPE: Scope = Scope::Chat;
+    const DESCRIPTION: &'static str = "Timezone of the chat, e.g. \"Europe/Moscow\"";
+
+    type Value = String;
+
+    fn validate(value: &Self::Value) -> anyhow::Result<()> {
+        // Empty value means UTC
+        if !value.is_empty() {
+            Self::parse(value)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct QuietHours {
+    /// Local time in the chat timezone, "HH:MM"
+    pub start: String,
+    pub end: String,
+}
+
+impl QuietHours {
+    pub fn contains(&self, time: chrono::NaiveTime) -> anyhow::Result<bool> {
+        let start = parse_time(&self.start)?;
+        let end = parse_time(&self.end)?;
+
+        // Quiet hours can span midnight, e.g. 23:00-07:00
+        Ok(if start <= end {
+            start <= time && time < end
+        } else {
+            start <= time || time < end
+        })
+    }
+}
+
+impl Default for QuietHours {
+    fn default() -> Self {
+        Self {
+            start: "00:00".to_string(),
+            end: "00:00".to_string(),
+        }
+    }
+}
+
+fn parse_time(value: &str) -> anyhow::Result<chrono::NaiveTime> {
+    chrono::NaiveTime::parse_from_str(value, "%H:%M")
+        .map_err(|e| anyhow!("Cannot parse time {}: {}", value, e))
+}
+
+/// Zero values mean no limit
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ReplyPolicyValue {
+    pub min_reply_interval_seconds: u64,
+    pub slowpoke_commands_per_user_per_hour: u32,
+    pub max_replies_per_day: u32,
+    pub quiet_hours: Option<QuietHours>,
+}
+
+pub struct ReplyPolicy;
+
+impl Setting for ReplyPolicy {
+    const KEY: &'static str = "reply_policy";
+    const SCOPE: Scope = Scope::Chat;
+    const DESCRIPTION: &'static str = "Limits for slowpoke replies. Zero means no limit, \
+        quiet hours are in the chat timezone, e.g. {\"start\": \"23:00\", \"end\": \"07:00\"}";
+
+    type Value = ReplyPolicyValue;
+
+    fn validate(value: &Self::Value) -> anyhow::Result<()> {
+        if let Some(quiet_hours) = &value.quiet_hours {
+            parse_time(&quiet_hours.start)?;
+            parse_time(&quiet_hours.end)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApprovalStatus {
+    Pending,
+    Approved,
+    Denied,
+}
+
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ChatApprovalValue {
+    /// Not set for chats, which were never reviewed
+    pub status: Option<ApprovalStatus>,
+    /// Unix time, when the owner was asked about the chat
+    pub requested_at: Option<i64>,
+}
+
+pub struct ChatApproval;
+
+impl Setting for ChatApproval {
+    const KEY: &'static str = "approval";
+    const SCOPE: Scope = Scope::Chat;
+    const DESCRIPTION: &'static str = "Whether the owner allowed the bot to work in the chat. \
+        Used only in the allowlist mode";
+    const IS_INTERNAL: bool = true;
+
+    type Value = ChatApprovalValue;
+}
+
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ExemptionsValue {
+    /// Chats and channels, forwards from which are never slowpoked
+    pub forward_sources: Vec<i64>,
+    /// Users and bots, whose messages are ignored
+    pub users: Vec<u64>,
+    /// Domains, which are excluded from link detection together with their subdomains
+    pub domains: Vec<String>,
+}
+
+impl ExemptionsValue {
+    pub fn is_domain_exempt(&self, host: &str) -> bool {
+        self.domains.iter().any(|domain| {
+            host == domain
+                || host
+                    .strip_suffix(domain.as_str())
+                    .is_some_and(|subdomain| subdomain.ends_with('.'))
+        })
+    }
+}
+
+pub struct Exemptions;
+
+impl Exemptions {
+    pub fn normalize_domain(value: &str) -> anyhow::Result<String> {
+        let domain = value.trim().trim_end_matches('.').to_lowercase();
+        let domain = domain.strip_prefix("www.").unwrap_or(&domain).to_string();
+
+        if domain.is_empty() || domain.contains(|c: char| c.is_whitespace() || c == '/') {
+            bail!("Invalid domain: {}", value);
+        }
+        Ok(domain)
+    }
+}
+
+impl Setting for Exemptions {
+    const KEY: &'static str = "exemptions";
+    const SCOPE: Scope = Scope::Chat;
+    const DESCRIPTION: &'static str = "Forward sources, users and link domains, which are never \
+        slowpoked in the chat. Managed by /exempt and /unexempt";
+
+    type Value = ExemptionsValue;
+
+    fn validate(value: &Self::Value) -> anyhow::Result<()> {
+        for domain in &value.domains {
+            if Self::normalize_domain(domain)? != *domain {
+                bail!("Domain {} has to be in lowercase without \"www.\"", domain);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ActionMode {
+    /// Only reply with a slowpoke
+    #[default]
+    Reply,
+    /// Reply and delete the duplicate after a delay
+    ReplyAndDelete,
+    /// Delete the duplicate at once and leave a short notice
+    Delete,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct DuplicateActionValue {
+    pub mode: ActionMode,
+    /// Used only in the `reply_and_delete` mode
+    pub delete_after_seconds: u64,
+}
+
+impl Default for DuplicateActionValue {
+    fn default() -> Self {
+        Self {
+            mode: ActionMode::Reply,
+            delete_after_seconds: 60,
+        }
+    }
+}
+
+// Telegram doesn't let bots delete messages older than 48 hours, an hour is left for delays
+const MAX_DELETE_AFTER_SECONDS: u64 = 47 * 60 * 60;
+
+pub struct DuplicateAction;
+
+impl Setting for DuplicateAction {
+    const KEY: &'static str = "duplicate_action";
+    const SCOPE: Scope = Scope::Chat;
+    const DESCRIPTION: &'static str = "What to do with duplicates: reply, reply_and_delete or \
+        delete. Deletion requires the right to delete messages. Managed by /action";
+
+    type Value = DuplicateActionValue;
+
+    fn validate(value: &Self::Value) -> anyhow::Result<()> {
+        if value.delete_after_seconds > MAX_DELETE_AFTER_SECONDS {
+            bail!(
+                "Duplicates can be deleted not later than {} seconds after they are posted",
+                MAX_DELETE_AFTER_SECONDS
+            );
+        }
+        Ok(())
+    }
+}
+
+pub struct ChannelAlertChat;
+
+impl Setting for ChannelAlertChat {
+    const KEY: &'static str = "channel_alert_chat";
+    const SCOPE: Scope = Scope::Chat;
+    const DESCRIPTION: &'static str = "Chat, which gets warnings about reposts in the channel. \
+        Without it the channel creator gets them in private messages. Managed by /alerts";
+
+    type Value = Option<i64>;
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkedChannelMode {
+    /// Automatic forwards of channel posts are not checked and not remembered
+    #[default]
+    Ignore,
+    /// Automatic forwards are remembered, so members reposting a channel post get a slowpoke,
+    /// but they never get a slowpoke themselves
+    Record,
+    /// Like `ignore`, and forwards from the linked channel by members are not checked either
+    Exempt,
+}
+
+pub struct LinkedChannel;
+
+impl Setting for LinkedChannel {
+    const KEY: &'static str = "linked_channel";
+    const SCOPE: Scope = Scope::Chat;
+    const DESCRIPTION: &'static str = "How posts of the channel, which the chat discusses, are \
+        handled: ignore, record or exempt";
+
+    type Value = LinkedChannelMode;
+}
+
+pub struct LinkedChannelId;
+
+impl Setting for LinkedChannelId {
+    const KEY: &'static str = "linked_channel_id";
+    const SCOPE: Scope = Scope::Chat;
+    const DESCRIPTION: &'static str =
+        "Channel, which the chat discusses. Learned from automatic forwards";
+    const IS_INTERNAL: bool = true;
+
+    type Value = Option<i64>;
+}
+
+pub struct RemovedAt;
+
+impl Setting for RemovedAt {
+    const KEY: &'static str = "removed_at";
+    const SCOPE: Scope = Scope::Chat;
+    const DESCRIPTION: &'static str = "Unix time, when the bot was removed from the chat. \
+        Data of removed chats is archived or deleted after a grace period";
+    const IS_INTERNAL: bool = true;
+
+    type Value = Option<i64>;
+}
+
+pub struct OptedOut;
+
+impl Setting for OptedOut {
+    const KEY: &'static str = "opted_out";
+    const SCOPE: Scope = Scope::User;
+    const DESCRIPTION: &'static str =
+        "The user is never slowpoked and their messages are not recorded. Managed by /optout";
+    const IS_INTERNAL: bool = true;
+
+    type Value = bool;
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct PersonalDataRetentionValue {
+    /// Zero means, that user ids are kept as long as the records themselves
+    pub days: u32,
+}
+
+impl Default for PersonalDataRetentionValue {
+    fn default() -> Self {
+        Self { days: 30 }
+    }
+}
+
+pub struct PersonalDataRetention;
+
+impl Setting for PersonalDataRetention {
+    const KEY: &'static str = "personal_data_retention";
+    const SCOPE: Scope = Scope::Chat;
+    const DESCRIPTION: &'static str = "How many days user ids are kept in the chat records. \
+        Content fingerprints are kept independently of it";
+
+    type Value = PersonalDataRetentionValue;
+}
+
+pub struct MessageRetention;
+
+impl Setting for MessageRetention {
+    const KEY: &'static str = "message_retention_days";
+    const SCOPE: Scope = Scope::Chat;
+    const DESCRIPTION: &'static str =
+        "How many days message fingerprints are kept, null for the bot-wide MAX_MESSAGE_AGE_IN_SECONDS";
+
+    type Value = Option<u32>;
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct DigestValue {
+    pub is_enabled: bool,
+    /// The digest covers this many days and is sent once per this many days
+    pub period_days: u32,
+    /// Hour of the day in the chat timezone, after which the digest is sent
+    pub hour: u32,
+}
+
+impl Default for DigestValue {
+    fn default() -> Self {
+        Self {
+            is_enabled: false,
+            period_days: 7,
+            hour: 18,
+        }
+    }
+}
+
+pub struct Digest;
+
+impl Setting for Digest {
+    const KEY: &'static str = "digest";
+    const SCOPE: Scope = Scope::Chat;
+    const DESCRIPTION: &'static str =
+        "Scheduled digest of the most reposted content and the biggest slowpokes of the chat";
+
+    type Value = DigestValue;
+
+    fn validate(value: &Self::Value) -> anyhow::Result<()> {
+        if value.period_days == 0 {
+            bail!("Digest period cannot be zero");
+        }
+        if value.hour > 23 {
+            bail!("Hour must be between 0 and 23");
+        }
+        Ok(())
+    }
+}
+
+pub struct DigestSentAt;
+
+impl Setting for DigestSentAt {
+    const KEY: &'static str = "digest_sent_at";
+    const SCOPE: Scope = Scope::Chat;
+    const DESCRIPTION: &'static str = "Unix time, when the last digest was sent to the chat";
+    const IS_INTERNAL: bool = true;
+
+    type Value = Option<i64>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exemptions(domains: &[&str]) -> ExemptionsValue {
+        ExemptionsValue {
+            domains: domains.iter().map(ToString::to_string).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn quiet_hours(start: &str, end: &str) -> QuietHours {
+        QuietHours {
+            start: start.to_string(),
+            end: end.to_string(),
+        }
+    }
+
+    fn time(value: &str) -> chrono::NaiveTime {
+        parse_time(value).unwrap()
+    }
+
+    #[test]
+    fn quiet_hours_within_a_day() {
+        let quiet_hours = quiet_hours("13:00", "15:30");
+        assert!(!quiet_hours.contains(time("12:59")).unwrap());
+        assert!(quiet_hours.contains(time("13:00")).unwrap());
+        assert!(quiet_hours.contains(time("15:29")).unwrap());
+        assert!(!quiet_hours.contains(time("15:30")).unwrap());
+    }
+
+    #[test]
+    fn quiet_hours_across_midnight() {
+        let quiet_hours = quiet_hours("23:00", "07:00");
+        assert!(quiet_hours.contains(time("23:00")).unwrap());
+        assert!(quiet_hours.contains(time("00:00")).unwrap());
+        assert!(quiet_hours.contains(time("06:59")).unwrap());
+        assert!(!quiet_hours.contains(time("07:00")).unwrap());
+        assert!(!quiet_hours.contains(time("12:00")).unwrap());
+        assert!(!quiet_hours.contains(time("22:59")).unwrap());
+    }
+
+    #[test]
+    fn equal_quiet_hours_bounds_mean_no_quiet_hours() {
+        let quiet_hours = QuietHours::default();
+        assert!(!quiet_hours.contains(time("00:00")).unwrap());
+        assert!(!quiet_hours.contains(time("12:00")).unwrap());
+    }
+
+    #[test]
+    fn invalid_quiet_hours_are_rejected() {
+        assert!(quiet_hours("25:00", "07:00")
+            .contains(time("12:00"))
+            .is_err());
+        assert!(ReplyPolicy::validate(&ReplyPolicyValue {
+            quiet_hours: Some(quiet_hours("23:00", "7")),
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn exempt_domain_covers_subdomains() {
+        let exemptions = exemptions(&["example.com"]);
+        assert!(exemptions.is_domain_exempt("example.com"));
+        assert!(exemptions.is_domain_exempt("news.example.com"));
+        assert!(exemptions.is_domain_exempt("a.b.example.com"));
+    }
+
+    #[test]
+    fn exempt_domain_doesnt_cover_similar_names() {
+        let exemptions = exemptions(&["example.com"]);
+        assert!(!exemptions.is_domain_exempt("badexample.com"));
+        assert!(!exemptions.is_domain_exempt("example.com.evil.org"));
+        assert!(!exemptions.is_domain_exempt("com"));
+    }
+}
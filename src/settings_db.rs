@@ -1,6 +1,126 @@
-#[derive(Default, serde::Serialize, serde::Deserialize)]
-pub struct ImageFileId {
-    file_id: std::string::String,
+// Legacy key, which was used to store a bincode-serialized file id before typed settings
+const LEGACY_IMAGE_FILE_ID_KEY: &str = "image_file_id";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scope {
+    Global,
+    Chat,
+    User,
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Scope::Global => write!(f, "global"),
+            Scope::Chat => write!(f, "chat"),
+            Scope::User => write!(f, "user"),
+        }
+    }
+}
+
+/// Where a setting value lives. Chat-scoped and user-scoped settings are stored separately for
+/// every chat and user.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Namespace {
+    Global,
+    Chat(i64),
+    User(u64),
+}
+
+impl Namespace {
+    /// Namespace for a setting with the scope, when it is accessed from the chat. User-scoped
+    /// settings are managed by the users themselves and cannot be accessed this way.
+    pub fn for_chat(scope: Scope, chat_id: i64) -> anyhow::Result<Self> {
+        match scope {
+            Scope::Global => Ok(Namespace::Global),
+            Scope::Chat => Ok(Namespace::Chat(chat_id)),
+            Scope::User => bail!("user settings cannot be changed in a chat"),
+        }
+    }
+
+    pub fn scope(&self) -> Scope {
+        match self {
+            Namespace::Global => Scope::Global,
+            Namespace::Chat(_) => Scope::Chat,
+            Namespace::User(_) => Scope::User,
+        }
+    }
+
+    fn prefix(&self) -> String {
+        match self {
+            Namespace::Global => "global/".to_string(),
+            Namespace::Chat(chat_id) => format!("chat/{}/", chat_id),
+            Namespace::User(user_id) => format!("user/{}/", user_id),
+        }
+    }
+}
+
+/// Schema of a single setting.
+///
+/// Values are stored as JSON together with `VERSION`, so new fields can be added to a value type
+/// without breaking already stored data as long as they have `#[serde(default)]`.
+pub trait Setting {
+    const KEY: &'static str;
+    const SCOPE: Scope;
+    const DESCRIPTION: &'static str;
+    const VERSION: u32 = 1;
+    /// State, which only the bot changes. It is not listed and cannot be set or reset by name.
+    const IS_INTERNAL: bool = false;
+
+    type Value: serde::Serialize + serde::de::DeserializeOwned + Default;
+
+    fn validate(_value: &Self::Value) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Type-erased view of a [`Setting`], used for listing and editing settings by name.
+pub struct SettingDescriptor {
+    pub key: &'static str,
+    pub scope: Scope,
+    pub description: &'static str,
+    pub is_internal: bool,
+    version: u32,
+    default_value: fn() -> anyhow::Result<serde_json::Value>,
+    normalize_value: fn(serde_json::Value) -> anyhow::Result<serde_json::Value>,
+}
+
+impl SettingDescriptor {
+    pub const fn of<S: Setting>() -> Self {
+        Self {
+            key: S::KEY,
+            scope: S::SCOPE,
+            description: S::DESCRIPTION,
+            is_internal: S::IS_INTERNAL,
+            version: S::VERSION,
+            default_value: default_value::<S>,
+            normalize_value: normalize_value::<S>,
+        }
+    }
+}
+
+fn default_value<S: Setting>() -> anyhow::Result<serde_json::Value> {
+    Ok(serde_json::to_value(S::Value::default())?)
+}
+
+// Parses a value into the setting type and validates it. Fields missing in the input get their
+// default values, so the result is always a complete value.
+fn normalize_value<S: Setting>(value: serde_json::Value) -> anyhow::Result<serde_json::Value> {
+    let value: S::Value = serde_json::from_value(value)?;
+    S::validate(&value)?;
+    Ok(serde_json::to_value(value)?)
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct StoredValue {
+    version: u32,
+    value: serde_json::Value,
+}
+
+pub struct SettingEntry {
+    pub descriptor: &'static SettingDescriptor,
+    pub value: serde_json::Value,
+    pub is_default: bool,
 }
 
 pub struct SettingsDb {
@@ -10,20 +130,413 @@ pub struct SettingsDb {
 impl SettingsDb {
     pub fn new(db_location: &std::path::Path) -> anyhow::Result<Self> {
         let db = sled::open(db_location)?;
+        let settings_db = Self { db };
+        settings_db.migrate_legacy_settings()?;
+        Ok(settings_db)
+    }
+
+    /// Fails, if the database is open in another place, e.g. by the running bot. sled locks the
+    /// database for the whole time it is open.
+    pub fn ensure_not_in_use(db_location: &std::path::Path) -> anyhow::Result<()> {
+        if db_location.exists() {
+            sled::open(db_location).map_err(|e| {
+                anyhow!(
+                    "Settings database {} is in use: {}",
+                    db_location.display(),
+                    e
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Database, which is removed when dropped
+    #[cfg(test)]
+    pub fn temporary() -> anyhow::Result<Self> {
+        let db = sled::Config::new().temporary(true).open()?;
         Ok(Self { db })
     }
 
-    pub fn get_setting(&self, name: &str) -> anyhow::Result<String> {
-        let bytes = self.db.get(name)?.ok_or(anyhow!("Setting not found"))?;
+    pub fn get<S: Setting>(&self, namespace: Namespace) -> anyhow::Result<S::Value> {
+        let key = Self::storage_key(namespace, S::SCOPE, S::KEY)?;
+
+        match self.load(&key, S::VERSION)? {
+            Some(value) => Ok(serde_json::from_value(value)?),
+            None => Ok(S::Value::default()),
+        }
+    }
+
+    pub fn set<S: Setting>(&self, namespace: Namespace, value: &S::Value) -> anyhow::Result<()> {
+        S::validate(value)?;
+        let key = Self::storage_key(namespace, S::SCOPE, S::KEY)?;
+        self.store(key, S::VERSION, serde_json::to_value(value)?)
+    }
+
+    /// Changes the stored value atomically, so concurrent updates of the same setting are not lost.
+    /// The function can be called several times, if the value is changed meanwhile.
+    pub fn update<S: Setting>(
+        &self,
+        namespace: Namespace,
+        mut update: impl FnMut(&mut S::Value),
+    ) -> anyhow::Result<()> {
+        let key = Self::storage_key(namespace, S::SCOPE, S::KEY)?;
+
+        let mut result = Ok(());
+        self.db.update_and_fetch(&key, |bytes| {
+            let updated = bytes
+                .map(|bytes| Self::decode(&key, bytes, S::VERSION))
+                .transpose()
+                .and_then(|value| {
+                    let mut value: S::Value = match value {
+                        Some(value) => serde_json::from_value(value)?,
+                        None => S::Value::default(),
+                    };
+                    update(&mut value);
+                    S::validate(&value)?;
+                    Self::encode(S::VERSION, serde_json::to_value(value)?)
+                });
+
+            match updated {
+                Ok(updated) => {
+                    result = Ok(());
+                    Some(updated)
+                }
+                // The stored value is kept as is
+                Err(e) => {
+                    result = Err(e);
+                    bytes.map(|bytes| bytes.to_vec())
+                }
+            }
+        })?;
+
+        result
+    }
+
+    pub fn reset<S: Setting>(&self, namespace: Namespace) -> anyhow::Result<()> {
+        let key = Self::storage_key(namespace, S::SCOPE, S::KEY)?;
+        self.db.remove(key)?;
+        Ok(())
+    }
+
+    pub fn descriptor(&self, key: &str) -> anyhow::Result<&'static SettingDescriptor> {
+        crate::settings::ALL
+            .iter()
+            .find(|descriptor| descriptor.key == key)
+            .ok_or_else(|| anyhow!("Unknown setting: {}", key))
+    }
+
+    fn editable_descriptor(&self, key: &str) -> anyhow::Result<&'static SettingDescriptor> {
+        let descriptor = self.descriptor(key)?;
+        if descriptor.is_internal {
+            bail!("Setting {} is managed by the bot", key);
+        }
+        Ok(descriptor)
+    }
 
-        let setting = bincode::deserialize(&bytes)?;
+    /// Returns the stored value or the default one for settings which were never set.
+    pub fn get_raw(&self, namespace: Namespace, key: &str) -> anyhow::Result<SettingEntry> {
+        let descriptor = self.descriptor(key)?;
+        let storage_key = Self::storage_key(namespace, descriptor.scope, descriptor.key)?;
 
-        Ok(setting)
+        match self.load(&storage_key, descriptor.version)? {
+            Some(value) => Ok(SettingEntry {
+                descriptor,
+                value: (descriptor.normalize_value)(value)?,
+                is_default: false,
+            }),
+            None => Ok(SettingEntry {
+                descriptor,
+                value: (descriptor.default_value)()?,
+                is_default: true,
+            }),
+        }
     }
 
-    pub fn add_setting(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
-        let bytes = bincode::serialize(&value)?;
-        self.db.insert(key, bytes)?;
+    pub fn set_raw(
+        &self,
+        namespace: Namespace,
+        key: &str,
+        value: serde_json::Value,
+    ) -> anyhow::Result<()> {
+        let descriptor = self.editable_descriptor(key)?;
+        let storage_key = Self::storage_key(namespace, descriptor.scope, descriptor.key)?;
+        let value = (descriptor.normalize_value)(value)?;
+        self.store(storage_key, descriptor.version, value)
+    }
+
+    pub fn reset_raw(&self, namespace: Namespace, key: &str) -> anyhow::Result<()> {
+        let descriptor = self.editable_descriptor(key)?;
+        let storage_key = Self::storage_key(namespace, descriptor.scope, descriptor.key)?;
+        self.db.remove(storage_key)?;
         Ok(())
     }
+
+    /// Lists all settings, which are applicable to the namespace, except the internal ones.
+    pub fn list(&self, namespace: Namespace) -> anyhow::Result<Vec<SettingEntry>> {
+        crate::settings::ALL
+            .iter()
+            .filter(|descriptor| descriptor.scope == namespace.scope() && !descriptor.is_internal)
+            .map(|descriptor| self.get_raw(namespace, descriptor.key))
+            .collect()
+    }
+
+    /// Chats, which have a value of the setting.
+    pub fn chats_with<S: Setting>(&self) -> anyhow::Result<Vec<i64>> {
+        let mut chat_ids = Vec::new();
+
+        for entry in self.db.scan_prefix("chat/") {
+            let (key, _) = entry?;
+            let key = String::from_utf8(key.to_vec())?;
+            if let Some((Namespace::Chat(chat_id), key)) = Self::parse_storage_key(&key) {
+                if key == S::KEY {
+                    chat_ids.push(chat_id);
+                }
+            }
+        }
+
+        Ok(chat_ids)
+    }
+
+    /// Removes all chat-scoped values of the chat.
+    pub fn remove_chat(&self, chat_id: i64) -> anyhow::Result<()> {
+        for entry in self.db.scan_prefix(Namespace::Chat(chat_id).prefix()) {
+            let (key, _) = entry?;
+            self.db.remove(key)?;
+        }
+
+        Ok(())
+    }
+
+    /// Moves chat-scoped values of the old chat to the new one. Values of the old chat win, since
+    /// the new chat can only have values, which were set automatically so far.
+    pub fn move_chat(&self, old_chat_id: i64, new_chat_id: i64) -> anyhow::Result<()> {
+        let old_prefix = Namespace::Chat(old_chat_id).prefix();
+        let new_prefix = Namespace::Chat(new_chat_id).prefix();
+
+        for entry in self.db.scan_prefix(&old_prefix) {
+            let (key, value) = entry?;
+            let key = String::from_utf8(key.to_vec())?;
+            let new_key = format!("{}{}", new_prefix, &key[old_prefix.len()..]);
+            self.db.insert(new_key, value)?;
+            self.db.remove(key)?;
+        }
+
+        Ok(())
+    }
+
+    /// Dumps stored values of the chat with keys relative to the chat namespace.
+    pub fn export_chat(
+        &self,
+        chat_id: i64,
+    ) -> anyhow::Result<std::collections::BTreeMap<String, serde_json::Value>> {
+        let prefix = Namespace::Chat(chat_id).prefix();
+        let mut entries = std::collections::BTreeMap::new();
+
+        for entry in self.db.scan_prefix(&prefix) {
+            let (key, bytes) = entry?;
+            let key = String::from_utf8(key.to_vec())?;
+            entries.insert(
+                key[prefix.len()..].to_string(),
+                serde_json::from_slice(&bytes)?,
+            );
+        }
+
+        Ok(entries)
+    }
+
+    /// Dumps all stored values, so they can be imported into another database.
+    pub fn export(&self) -> anyhow::Result<std::collections::BTreeMap<String, serde_json::Value>> {
+        let mut entries = std::collections::BTreeMap::new();
+
+        for entry in self.db.iter() {
+            let (key, bytes) = entry?;
+            let key = String::from_utf8(key.to_vec())?;
+            entries.insert(key, serde_json::from_slice(&bytes)?);
+        }
+
+        Ok(entries)
+    }
+
+    /// Replaces the whole content of the database with exported values.
+    pub fn import(
+        &self,
+        entries: &std::collections::BTreeMap<String, serde_json::Value>,
+    ) -> anyhow::Result<()> {
+        Self::validate_export(entries)?;
+
+        self.db.clear()?;
+        for (key, value) in entries {
+            self.db.insert(key.as_str(), serde_json::to_vec(value)?)?;
+        }
+        self.db.flush()?;
+
+        Ok(())
+    }
+
+    pub fn validate_export(
+        entries: &std::collections::BTreeMap<String, serde_json::Value>,
+    ) -> anyhow::Result<()> {
+        for (storage_key, value) in entries {
+            let (namespace, key) = Self::parse_storage_key(storage_key)
+                .ok_or_else(|| anyhow!("Malformed setting key: {}", storage_key))?;
+            let descriptor = crate::settings::ALL
+                .iter()
+                .find(|descriptor| descriptor.key == key)
+                .ok_or_else(|| anyhow!("Unknown setting: {}", storage_key))?;
+            let stored: StoredValue = serde_json::from_value(value.clone())?;
+
+            if descriptor.scope != namespace.scope() || stored.version > descriptor.version {
+                bail!(
+                    "Setting {} is not compatible with this version",
+                    storage_key
+                );
+            }
+            (descriptor.normalize_value)(stored.value)
+                .map_err(|e| anyhow!("Setting {} is invalid: {}", storage_key, e))?;
+        }
+
+        Ok(())
+    }
+
+    /// Current versions of all known settings.
+    pub fn schema_versions() -> std::collections::BTreeMap<String, u32> {
+        crate::settings::ALL
+            .iter()
+            .map(|descriptor| (descriptor.key.to_string(), descriptor.version))
+            .collect()
+    }
+
+    fn parse_storage_key(storage_key: &str) -> Option<(Namespace, &str)> {
+        if let Some(key) = storage_key.strip_prefix("global/") {
+            return Some((Namespace::Global, key));
+        }
+
+        if let Some(key) = storage_key.strip_prefix("user/") {
+            let (user_id, key) = key.split_once('/')?;
+            return Some((Namespace::User(user_id.parse().ok()?), key));
+        }
+
+        let (chat_id, key) = storage_key.strip_prefix("chat/")?.split_once('/')?;
+        Some((Namespace::Chat(chat_id.parse().ok()?), key))
+    }
+
+    fn load(
+        &self,
+        storage_key: &str,
+        supported_version: u32,
+    ) -> anyhow::Result<Option<serde_json::Value>> {
+        match self.db.get(storage_key)? {
+            Some(bytes) => Ok(Some(Self::decode(storage_key, &bytes, supported_version)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn store(&self, key: String, version: u32, value: serde_json::Value) -> anyhow::Result<()> {
+        self.db.insert(key, Self::encode(version, value)?)?;
+        Ok(())
+    }
+
+    fn decode(
+        storage_key: &str,
+        bytes: &[u8],
+        supported_version: u32,
+    ) -> anyhow::Result<serde_json::Value> {
+        let stored: StoredValue = serde_json::from_slice(bytes)?;
+        if stored.version > supported_version {
+            bail!(
+                "Setting {} has version {}, but only versions up to {} are supported",
+                storage_key,
+                stored.version,
+                supported_version
+            );
+        }
+        Ok(stored.value)
+    }
+
+    fn encode(version: u32, value: serde_json::Value) -> anyhow::Result<Vec<u8>> {
+        Ok(serde_json::to_vec(&StoredValue { version, value })?)
+    }
+
+    fn storage_key(namespace: Namespace, scope: Scope, key: &str) -> anyhow::Result<String> {
+        if namespace.scope() != scope {
+            bail!(
+                "Setting {} has {} scope and cannot be used in {} scope",
+                key,
+                scope,
+                namespace.scope()
+            );
+        }
+
+        Ok(format!("{}{}", namespace.prefix(), key))
+    }
+
+    fn migrate_legacy_settings(&self) -> anyhow::Result<()> {
+        if let Some(bytes) = self.db.get(LEGACY_IMAGE_FILE_ID_KEY)? {
+            let file_id: String = bincode::deserialize(&bytes)?;
+            self.set::<crate::settings::ReplyImage>(
+                Namespace::Global,
+                &Some(crate::settings::ImageFileId { file_id }),
+            )?;
+            self.db.remove(LEGACY_IMAGE_FILE_ID_KEY)?;
+            log::info!("Legacy image file id setting was migrated");
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings;
+
+    #[test]
+    fn concurrent_updates_are_not_lost() {
+        let settings_db = SettingsDb::temporary().unwrap();
+        let namespace = Namespace::Chat(-1);
+
+        std::thread::scope(|scope| {
+            for user_id in 0..8 {
+                let settings_db = &settings_db;
+                scope.spawn(move || {
+                    settings_db
+                        .update::<settings::Exemptions>(namespace, |exemptions| {
+                            exemptions.users.push(user_id)
+                        })
+                        .unwrap()
+                });
+            }
+        });
+
+        let mut users = settings_db
+            .get::<settings::Exemptions>(namespace)
+            .unwrap()
+            .users;
+        users.sort_unstable();
+        assert_eq!(users, (0..8).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn invalid_updates_keep_the_stored_value() {
+        let settings_db = SettingsDb::temporary().unwrap();
+        let namespace = Namespace::Chat(-1);
+        settings_db
+            .update::<settings::Exemptions>(namespace, |exemptions| {
+                exemptions.domains.push("example.com".to_string())
+            })
+            .unwrap();
+
+        assert!(settings_db
+            .update::<settings::Exemptions>(namespace, |exemptions| {
+                exemptions.domains.push(String::new())
+            })
+            .is_err());
+        assert_eq!(
+            settings_db
+                .get::<settings::Exemptions>(namespace)
+                .unwrap()
+                .domains,
+            ["example.com"]
+        );
+    }
 }
@@ -0,0 +1,161 @@
+use crate::broadcast;
+use crate::db;
+use crate::logging::TracedRequest;
+use crate::parameters;
+use crate::settings;
+use crate::settings_db;
+use teloxide::prelude::*;
+
+// Recent activity and detections are reported for this period
+const ACTIVITY_PERIOD_IN_HOURS: u64 = 24;
+
+/// State of the running bot, which is not stored anywhere else
+pub struct RuntimeStatus {
+    started_at: std::time::Instant,
+    last_maintenance_at: std::sync::Mutex<Option<chrono::DateTime<chrono::Utc>>>,
+}
+
+impl RuntimeStatus {
+    pub fn new() -> Self {
+        Self {
+            started_at: std::time::Instant::now(),
+            last_maintenance_at: Default::default(),
+        }
+    }
+
+    pub fn record_maintenance(&self) {
+        *self
+            .last_maintenance_at
+            .lock()
+            .expect("Runtime status mutex is poisoned") = Some(chrono::Utc::now());
+    }
+}
+
+/// Text for the /status command
+pub async fn status_text(
+    bot: &AutoSend<Bot>,
+    settings_db: &settings_db::SettingsDb,
+    pool_factory: &db::SqliteDatabasePoolFactory,
+    parameters: &parameters::Parameters,
+    runtime: &RuntimeStatus,
+) -> anyhow::Result<String> {
+    let activity_period = std::time::Duration::from_secs(ACTIVITY_PERIOD_IN_HOURS * 60 * 60);
+    let chat_ids = pool_factory.list_existing_chats()?;
+    // The same chats, which get broadcasts
+    let active_chats = broadcast::target_chats(pool_factory, settings_db, parameters)?.len();
+
+    // Only databases, which changed recently, can have new detections, so others are not opened
+    let mut recently_changed_chats = 0;
+    let mut detections = std::collections::BTreeMap::<String, i64>::new();
+    for &chat_id in &chat_ids {
+        let is_active = pool_factory
+            .database_modified_at(chat_id)
+            .and_then(|modified_at| modified_at.elapsed().ok())
+            .is_some_and(|elapsed| elapsed < activity_period);
+        if !is_active {
+            continue;
+        }
+        recently_changed_chats += 1;
+
+        let client = pool_factory.create(chat_id).await?;
+        for (detector, count) in client
+            .detection_counts(ACTIVITY_PERIOD_IN_HOURS as i64)
+            .await?
+        {
+            *detections.entry(detector).or_default() += count;
+        }
+    }
+
+    let mut text = format!(
+        "Версия: {}\nРаботает: {}\nРежим: {}\nЧатов: {}, активных: {}\nЧатов с сообщениями за сутки: {}\nРазмер баз чатов: {:.1} МБ\nОткрытых баз: {}",
+        env!("CARGO_PKG_VERSION"),
+        format_duration(runtime.started_at.elapsed()),
+        if parameters.is_webhook_mode_enabled {
+            "webhook"
+        } else {
+            "long polling"
+        },
+        chat_ids.len(),
+        active_chats,
+        recently_changed_chats,
+        directory_size(&parameters.chat_database_root_path)? as f64 / 1024.0 / 1024.0,
+        pool_factory.open_pool_count()
+    );
+
+    text.push_str("\nДубликатов за сутки:");
+    if detections.is_empty() {
+        text.push_str(" нет");
+    }
+    for (detector, count) in detections {
+        text.push_str(&format!("\n  {}: {}", detector, count));
+    }
+
+    let last_maintenance_at = *runtime
+        .last_maintenance_at
+        .lock()
+        .expect("Runtime status mutex is poisoned");
+    text.push_str(&format!(
+        "\nПоследняя очистка: {}",
+        last_maintenance_at
+            .map(|time| time.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+            .unwrap_or_else(|| "ещё не было".to_string())
+    ));
+
+    text.push_str(&format!(
+        "\nИзображение для ответа: {}",
+        image_status(bot, settings_db).await?
+    ));
+
+    Ok(text)
+}
+
+// Telegram knows the file, if it can return its description
+async fn image_status(
+    bot: &AutoSend<Bot>,
+    settings_db: &settings_db::SettingsDb,
+) -> anyhow::Result<String> {
+    let image = match settings_db.get::<settings::ReplyImage>(settings_db::Namespace::Global)? {
+        Some(image) => image,
+        None => return Ok("не задано".to_string()),
+    };
+
+    Ok(match bot.get_file(image.file_id).traced().await {
+        Ok(_) => "действительно".to_string(),
+        Err(e) => {
+            log::warn!("The reply image is not available: {:?}", e);
+            format!("недействительно ({})", e)
+        }
+    })
+}
+
+// Includes the quarantined databases
+fn directory_size(path: &std::path::Path) -> anyhow::Result<u64> {
+    let entries = match std::fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut size = 0;
+    for entry in entries {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() {
+            directory_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+
+    Ok(size)
+}
+
+fn format_duration(duration: std::time::Duration) -> String {
+    let minutes = duration.as_secs() / 60;
+    format!(
+        "{} д. {} ч. {} мин.",
+        minutes / 60 / 24,
+        minutes / 60 % 24,
+        minutes % 60
+    )
+}
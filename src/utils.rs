@@ -1,3 +1,4 @@
+use crate::logging::TracedRequest;
 use teloxide::prelude::*;
 
 pub fn is_sender_an_owner(from: &Option<&teloxide::types::User>, owner_id: u64) -> bool {
@@ -8,31 +9,150 @@ pub fn is_sender_an_owner(from: &Option<&teloxide::types::User>, owner_id: u64)
     }
 }
 
+/// Time of the message or of its last edit. Time limits are counted from it, so a replay of
+/// recorded updates gives the same results as the original run.
+pub fn message_time(msg: &Message) -> chrono::DateTime<chrono::Utc> {
+    msg.edit_date().copied().unwrap_or(msg.date)
+}
+
+/// Owner of the bot or an administrator of the chat, where the message was sent
+pub async fn is_sender_an_admin(msg: &Message, bot: &AutoSend<Bot>, owner_id: u64) -> bool {
+    match msg.from() {
+        Some(user) => is_user_an_admin(&msg.chat, user.id, bot, owner_id).await,
+        None => false,
+    }
+}
+
+pub async fn is_user_an_admin(
+    chat: &teloxide::types::Chat,
+    user_id: UserId,
+    bot: &AutoSend<Bot>,
+    owner_id: u64,
+) -> bool {
+    if user_id.0 == owner_id {
+        return true;
+    }
+    if chat.is_private() {
+        return false;
+    }
+
+    match bot.get_chat_member(chat.id, user_id).traced().await {
+        Ok(member) => member.is_privileged(),
+        Err(e) => {
+            log::warn!(
+                "Cannot get the user {} in the chat {}: {:?}",
+                user_id,
+                chat.id,
+                e
+            );
+            false
+        }
+    }
+}
+
+/// Why a slowpoke is sent
+pub enum SlowpokeReason {
+    Command,
+    Duplicate {
+        fingerprint: crate::detection::Fingerprint,
+        /// Id of the stored fingerprint of the earlier message
+        fingerprint_id: i64,
+    },
+}
+
+impl SlowpokeReason {
+    /// Short name of the detector, which found the duplicate
+    pub fn detector(&self) -> &'static str {
+        match self {
+            SlowpokeReason::Command => "command",
+            SlowpokeReason::Duplicate { fingerprint, .. } => fingerprint.detector.as_str(),
+        }
+    }
+}
+
+impl std::fmt::Display for SlowpokeReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SlowpokeReason::Command => write!(f, "requested by the command"),
+            SlowpokeReason::Duplicate { fingerprint, .. } => match fingerprint.detector {
+                crate::detection::Detector::Forward => write!(
+                    f,
+                    "forward of the message {} was already seen",
+                    fingerprint.value
+                ),
+                crate::detection::Detector::Link => {
+                    write!(f, "link {} was already posted", fingerprint.value)
+                }
+                crate::detection::Detector::Text => write!(f, "the same text was already posted"),
+                crate::detection::Detector::Media => {
+                    write!(f, "media {} was already posted", fingerprint.value)
+                }
+            },
+        }
+    }
+}
+
+/// Sends a slowpoke as a reply to the message. The reply has a "not a duplicate" button, which
+/// marks the detection as a false positive, if it is known.
 pub async fn send_slowpoke(
     msg: Message,
     bot: AutoSend<Bot>,
-    settings_db: std::sync::Arc<tokio::sync::Mutex<crate::settings_db::SettingsDb>>,
+    settings_db: std::sync::Arc<crate::settings_db::SettingsDb>,
+    journal: std::sync::Arc<crate::journal::ReplyJournal>,
+    reason: SlowpokeReason,
+    detection_id: Option<i64>,
 ) -> anyhow::Result<()> {
-    match settings_db.lock().await.get_setting("image_file_id") {
-        Ok(value) => {
-            log::debug!("Image file id: {}", value);
+    journal.record(msg.chat.id.0, msg.id, &reason);
 
-            if let Err(e) = bot
-                .send_photo(msg.chat.id, teloxide::types::InputFile::file_id(value))
-                .reply_to_message_id(msg.id)
-                .await
-            {
-                log::warn!("Cannot send a response: {:?}", e);
-            }
+    let keyboard = crate::feedback::keyboard(detection_id);
+
+    match settings_db.get::<crate::settings::ReplyImage>(crate::settings_db::Namespace::Global) {
+        Ok(Some(image)) => {
+            log::debug!("Image file id: {}", image.file_id);
+
+            bot.send_photo(
+                msg.chat.id,
+                teloxide::types::InputFile::file_id(image.file_id),
+            )
+            .reply_to_message_id(msg.id)
+            .reply_markup(keyboard)
+            .traced()
+            .await?;
         }
-        Err(e) => {
-            log::warn!("Cannot get an image from database: {:?}", e);
+        result => {
+            if let Err(e) = result {
+                log::warn!("Cannot get an image from database: {:?}", e);
+            }
             static MISSED_SLOWPOKE_IN_DATABASE: &str = "Слоупоки закончились :(.";
             bot.send_message(msg.chat.id, MISSED_SLOWPOKE_IN_DATABASE)
                 .reply_to_message_id(msg.id)
+                .reply_markup(keyboard)
+                .traced()
                 .await?;
         }
     }
 
     Ok(())
 }
+
+/// Runs the function around the rest of the handler tree for every update. Passes every update
+/// through, so the allowed updates are decided by the branches.
+pub fn around_updates<F, Fut>(f: F) -> teloxide::dispatching::UpdateHandler<anyhow::Error>
+where
+    F: Fn(
+            dptree::di::DependencyMap,
+            dptree::Cont<'static, dptree::di::DependencyMap, anyhow::Result<()>>,
+        ) -> Fut
+        + Send
+        + Sync
+        + 'static,
+    Fut: std::future::Future<
+            Output = std::ops::ControlFlow<anyhow::Result<()>, dptree::di::DependencyMap>,
+        > + Send
+        + 'static,
+{
+    dptree::from_fn_with_description(
+        <teloxide::dispatching::DpHandlerDescription as dptree::HandlerDescription>::entry(),
+        f,
+    )
+}
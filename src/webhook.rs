@@ -1,3 +1,4 @@
+use crate::logging::TracedRequest;
 use teloxide::prelude::*;
 use tokio::sync::mpsc;
 
@@ -42,6 +43,7 @@ pub async fn webhook(
     let url = format!("https://{}{}", host, path);
 
     bot.set_webhook(url.parse().unwrap())
+        .traced()
         .await
         .expect("Cannot setup a webhook");
 